@@ -0,0 +1,256 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EPPMessage {
+    #[serde(rename = "{urn:ietf:params:xml:ns:epp-1.0}epp")]
+    message: EPPMessageType,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum EPPMessageType {
+    #[serde(rename = "{urn:ietf:params:xml:ns:epp-1.0}command")]
+    Command(EPPCommand),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EPPCommand {
+    #[serde(rename = "{urn:ietf:params:xml:ns:epp-1.0}login")]
+    login: EPPLogin,
+    #[serde(rename = "{urn:ietf:params:xml:ns:epp-1.0}clTRID")]
+    client_transaction_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EPPLogin {
+    #[serde(rename = "{urn:ietf:params:xml:ns:epp-1.0}clID")]
+    client_id: String,
+    #[serde(rename = "{urn:ietf:params:xml:ns:epp-1.0}pw")]
+    password: String,
+    #[serde(rename = "{urn:ietf:params:xml:ns:epp-1.0}options")]
+    options: EPPLoginOptions,
+    #[serde(rename = "{urn:ietf:params:xml:ns:epp-1.0}svcs")]
+    services: EPPLoginServices,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EPPLoginOptions {
+    #[serde(rename = "{urn:ietf:params:xml:ns:epp-1.0}version")]
+    version: String,
+    #[serde(rename = "{urn:ietf:params:xml:ns:epp-1.0}lang")]
+    language: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EPPLoginServices {
+    #[serde(rename = "{urn:ietf:params:xml:ns:epp-1.0}objURI")]
+    objects: Vec<String>,
+}
+
+macro_rules! flat_struct {
+    ($($field:ident)*) => {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Flat {
+            $($field: u32,)*
+        }
+    }
+}
+
+flat_struct!(
+    f00 f01 f02 f03 f04 f05 f06 f07 f08 f09 f10 f11 f12 f13 f14 f15 f16 f17 f18 f19
+    f20 f21 f22 f23 f24 f25 f26 f27 f28 f29 f30 f31 f32 f33 f34 f35 f36 f37 f38 f39
+    f40 f41 f42 f43 f44 f45 f46 f47 f48 f49 f50 f51 f52 f53 f54 f55 f56 f57 f58 f59
+    f60 f61 f62 f63 f64 f65 f66 f67 f68 f69 f70 f71 f72 f73 f74 f75 f76 f77 f78 f79
+    f80 f81 f82 f83 f84 f85 f86 f87 f88 f89 f90 f91 f92 f93 f94 f95 f96 f97 f98 f99
+);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FlatDoc {
+    flat: Flat,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Nested {
+    value: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    child: Option<Box<Nested>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct NestedDoc {
+    nested: Nested,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct List {
+    item: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ListDoc {
+    list: List,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Namespaced {
+    #[serde(rename = "{urn:foo}foo:f0")]
+    f0: u32,
+    #[serde(rename = "{urn:foo}foo:f1")]
+    f1: u32,
+    #[serde(rename = "{urn:foo}foo:f2")]
+    f2: u32,
+    #[serde(rename = "{urn:foo}foo:f3")]
+    f3: u32,
+    #[serde(rename = "{urn:foo}foo:f4")]
+    f4: u32,
+    #[serde(rename = "{urn:foo}foo:f5")]
+    f5: u32,
+    #[serde(rename = "{urn:foo}foo:f6")]
+    f6: u32,
+    #[serde(rename = "{urn:foo}foo:f7")]
+    f7: u32,
+    #[serde(rename = "{urn:foo}foo:f8")]
+    f8: u32,
+    #[serde(rename = "{urn:foo}foo:f9")]
+    f9: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct NamespacedDoc {
+    #[serde(rename = "{urn:foo}foo:doc")]
+    doc: Namespaced,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct NamespacedMapDoc {
+    #[serde(rename = "{urn:foo}foo:doc")]
+    doc: std::collections::BTreeMap<String, u32>,
+}
+
+fn epp_message() -> EPPMessage {
+    EPPMessage {
+        message: EPPMessageType::Command(EPPCommand {
+            login: EPPLogin {
+                client_id: "client".to_string(),
+                password: "password".to_string(),
+                options: EPPLoginOptions {
+                    version: "1.0".to_string(),
+                    language: "en".to_string(),
+                },
+                services: EPPLoginServices {
+                    objects: vec![
+                        "urn:ietf:params:xml:ns:domain-1.0".to_string(),
+                        "urn:ietf:params:xml:ns:contact-1.0".to_string(),
+                        "urn:ietf:params:xml:ns:host-1.0".to_string(),
+                    ],
+                },
+            },
+            client_transaction_id: "ABC-12345".to_string(),
+        })
+    }
+}
+
+fn nested(depth: u32) -> Nested {
+    Nested {
+        value: depth,
+        child: match depth {
+            0 => None,
+            d => Some(Box::new(nested(d - 1))),
+        },
+    }
+}
+
+fn list() -> ListDoc {
+    ListDoc {
+        list: List {
+            item: (0..1000).map(|i| i.to_string()).collect(),
+        }
+    }
+}
+
+fn serialize(c: &mut Criterion) {
+    let message = epp_message();
+    c.bench_function("to_string epp message", |b| b.iter(|| {
+        xml_serde::to_string(black_box(&message)).unwrap()
+    }));
+    let value = NestedDoc {
+        nested: nested(99),
+    };
+    c.bench_function("to_string 100 level struct", |b| b.iter(|| {
+        xml_serde::to_string(black_box(&value)).unwrap()
+    }));
+}
+
+fn deserialize(c: &mut Criterion) {
+    let xml = format!("<flat>{}</flat>", (0..100).map(|i| format!("<f{0:02}>{0}</f{0:02}>", i)).collect::<String>());
+    c.bench_function("from_str 100 field struct", |b| b.iter(|| {
+        xml_serde::from_str::<FlatDoc>(black_box(&xml)).unwrap()
+    }));
+}
+
+fn round_trip(c: &mut Criterion) {
+    let value = NestedDoc {
+        nested: nested(9),
+    };
+    c.bench_function("round trip 10 level struct", |b| b.iter(|| {
+        let xml = xml_serde::to_string(black_box(&value)).unwrap();
+        xml_serde::from_str::<NestedDoc>(&xml).unwrap()
+    }));
+}
+
+fn events(c: &mut Criterion) {
+    let value = list();
+    c.bench_function("to_events 1000 element list", |b| b.iter(|| {
+        xml_serde::to_events(black_box(&value)).unwrap()
+    }));
+}
+
+// The same documents written and read directly with xml-rs, as a baseline
+fn baseline(c: &mut Criterion) {
+    let value = list();
+    c.bench_function("xml-rs write 1000 element list", |b| b.iter(|| {
+        let mut out = vec![];
+        let mut writer = xml::writer::EventWriter::new(&mut out);
+        writer.write(xml::writer::XmlEvent::start_element("list")).unwrap();
+        for item in black_box(&value.list.item) {
+            writer.write(xml::writer::XmlEvent::start_element("item")).unwrap();
+            writer.write(xml::writer::XmlEvent::characters(item)).unwrap();
+            writer.write(xml::writer::XmlEvent::end_element()).unwrap();
+        }
+        writer.write(xml::writer::XmlEvent::end_element()).unwrap();
+        out
+    }));
+
+    let xml = xml_serde::to_string(&value).unwrap();
+    c.bench_function("from_str 1000 element list", |b| b.iter(|| {
+        xml_serde::from_str::<ListDoc>(black_box(&xml)).unwrap()
+    }));
+    c.bench_function("xml-rs read 1000 element list", |b| b.iter(|| {
+        xml::reader::EventReader::new(black_box(xml.as_bytes()))
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }));
+}
+
+// Field names are static, so their parsed tags are cached after the first use, while map keys
+// are only known at runtime and are parsed every time
+fn tag_cache(c: &mut Criterion) {
+    let value = NamespacedDoc {
+        doc: Namespaced {
+            f0: 0, f1: 1, f2: 2, f3: 3, f4: 4, f5: 5, f6: 6, f7: 7, f8: 8, f9: 9,
+        }
+    };
+    c.bench_function("to_string 10 cached tags", |b| b.iter(|| {
+        xml_serde::to_string(black_box(&value)).unwrap()
+    }));
+    let value = NamespacedMapDoc {
+        doc: (0..10).map(|i| (format!("{{urn:foo}}foo:f{}", i), i)).collect(),
+    };
+    c.bench_function("to_string 10 uncached tags", |b| b.iter(|| {
+        xml_serde::to_string(black_box(&value)).unwrap()
+    }));
+}
+
+criterion_group!(benches, serialize, deserialize, round_trip, events, baseline, tag_cache);
+criterion_main!(benches);
@@ -0,0 +1,7 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = xml_serde::from_str::<xml_serde::XmlValue>(std::str::from_utf8(data).unwrap_or(""));
+});
@@ -0,0 +1,59 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Kind {
+    #[serde(rename = "{urn:test}t:a")]
+    A(String),
+    #[serde(rename = "{urn:test}t:b")]
+    B {
+        #[serde(rename = "{urn:test}t:x")]
+        x: u32,
+    },
+    #[serde(rename = "{urn:test}t:c")]
+    C,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Inner {
+    #[serde(rename = "$attr:id")]
+    id: String,
+    #[serde(rename = "{urn:test}t:name")]
+    name: String,
+    #[serde(rename = "{urn:test}t:value", default)]
+    value: Option<u32>,
+    #[serde(rename = "{urn:test}t:item", default)]
+    items: Vec<String>,
+    #[serde(rename = "{urn:test}t:kind", default)]
+    kinds: Vec<Kind>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Doc {
+    #[serde(rename = "{urn:test}t:doc")]
+    doc: Inner,
+}
+
+// The first two bytes pick a position in the serialised document, and the rest are spliced in there
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    let doc = Doc {
+        doc: Inner {
+            id: "1".to_string(),
+            name: "a & b".to_string(),
+            value: Some(42),
+            items: vec!["x".to_string(), "y".to_string()],
+            kinds: vec![Kind::A("z".to_string()), Kind::B { x: 1 }, Kind::C],
+        }
+    };
+    let mut xml = xml_serde::to_string(&doc).unwrap().into_bytes();
+    let pos = u16::from_le_bytes([data[0], data[1]]) as usize % (xml.len() + 1);
+    xml.splice(pos..pos, data[2..].iter().copied());
+    if let Ok(s) = std::str::from_utf8(&xml) {
+        let _ = xml_serde::from_str::<Doc>(s);
+    }
+});
@@ -9,31 +9,197 @@ use crate::Tag;
 // pub trait XMLIter = Iterator<Item=xml::reader::Result<xml::reader::XmlEvent>>;
 type XmlRes = xml::reader::Result<xml::reader::XmlEvent>;
 
+const XSI_NAMESPACE: &str = "http://www.w3.org/2001/XMLSchema-instance";
+
+type SignificantEvents<I> = std::iter::Filter<itertools::MultiPeek<I>, fn(&XmlRes) -> bool>;
+
 pub struct Deserializer<I: Iterator<Item=XmlRes>> {
-    reader: itertools::MultiPeek<I>,
+    reader: itertools::MultiPeek<SignificantEvents<I>>,
     depth: u64,
     is_map_value: bool,
     is_greedy: bool,
     is_value: bool,
+    /// The next string is for a `$valueRaw` field, so text is kept escaped as markup
+    is_raw_value: bool,
     reset_peek_offset: u64,
+    config: DeserializerConfig,
+}
+
+/// Shows where the deserializer is up to; the events themselves can't be shown without
+/// consuming them from the reader
+impl<I: Iterator<Item=XmlRes>> std::fmt::Debug for Deserializer<I> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Deserializer")
+            .field("depth", &self.depth)
+            .field("is_map_value", &self.is_map_value)
+            .field("is_greedy", &self.is_greedy)
+            .field("is_value", &self.is_value)
+            .field("is_raw_value", &self.is_raw_value)
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
 }
 
 fn new_reader<I: IntoIterator<Item=XmlRes>>(iter: I) -> itertools::MultiPeek<impl Iterator<Item=XmlRes>> {
-    itertools::multipeek(Box::new(iter.into_iter().filter(|e| match e {
-        &Ok(xml::reader::XmlEvent::ProcessingInstruction { ..}) => {
+    itertools::multipeek(Box::new(iter.into_iter().filter(|e| match *e {
+        Ok(xml::reader::XmlEvent::ProcessingInstruction { ..}) => {
             trace!("discarding processing instruction: {:?}", e.as_ref().unwrap());
             false
         },
+        Ok(xml::reader::XmlEvent::Whitespace(_)) => {
+            trace!("discarding whitespace: {:?}", e.as_ref().unwrap());
+            false
+        },
         _ => true,
     })))
 }
 
+static EXTERNAL_ENTITY_RE: once_cell::sync::Lazy<regex::Regex> = once_cell::sync::Lazy::new(|| {
+    regex::Regex::new(r"<!ENTITY\s+(?:%\s+)?\S+\s+(?:SYSTEM|PUBLIC)\b").unwrap()
+});
+
+/// Options controlling how XML is parsed
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeserializerConfig {
+    /// Reject documents containing a `<!DOCTYPE>` declaration
+    pub forbid_dtd: bool,
+    /// Reject documents declaring external (`SYSTEM` or `PUBLIC`) entities
+    pub forbid_external_entities: bool,
+    /// Deserialize empty attribute values into `Option` fields as `None` rather than `Some("")`
+    pub empty_attr_is_none: bool,
+    /// Deserialize empty elements into `Option` fields as `None` rather than `Some("")`
+    pub empty_string_as_none: bool,
+    /// Namespace that fields named without one match elements in, as well as elements in no
+    /// namespace
+    pub default_namespace: Option<String>,
+    /// Reject text content longer than this many bytes, such as from entity expansion
+    pub max_text_length: Option<usize>,
+    /// Reject elements with more than this many attributes
+    pub max_attributes: Option<usize>,
+    /// Have the parser drop comments rather than pass them on as events; this is on by default
+    pub ignore_comments: bool,
+    /// Trim whitespace from around text; this is on by default
+    pub trim_whitespace: bool,
+    /// Treat text that's only whitespace as text, rather than dropping it; this is on by default
+    pub whitespace_to_characters: bool,
+    /// Replace references to undeclared entities with the replacement character, rather than
+    /// failing; this is on by default
+    pub replace_unknown_entity_references: bool,
+    /// Reject elements nested deeper than this
+    pub max_depth: Option<usize>,
+}
+
+impl Default for DeserializerConfig {
+    fn default() -> Self {
+        Self {
+            forbid_dtd: false,
+            forbid_external_entities: false,
+            empty_attr_is_none: false,
+            empty_string_as_none: false,
+            default_namespace: None,
+            max_text_length: None,
+            max_attributes: None,
+            ignore_comments: true,
+            trim_whitespace: true,
+            whitespace_to_characters: true,
+            replace_unknown_entity_references: true,
+            max_depth: None,
+        }
+    }
+}
+
+impl DeserializerConfig {
+    /// A configuration suitable for parsing untrusted input
+    ///
+    /// ```
+    /// let config = xml_serde::DeserializerConfig::secure();
+    /// let result = xml_serde::from_str_with_config::<String>(
+    ///     r#"<!DOCTYPE a [<!ENTITY x SYSTEM "file:///etc/passwd">]><a>&x;</a>"#, config,
+    /// );
+    /// assert_eq!(result, Err(xml_serde::Error::SecurityViolation("DTD not allowed")));
+    /// ```
+    pub fn secure() -> Self {
+        Self {
+            forbid_dtd: true,
+            forbid_external_entities: true,
+            max_text_length: Some(1024 * 1024),
+            max_attributes: Some(256),
+            ..Self::default()
+        }
+    }
+
+    fn parser_config(&self) -> xml::ParserConfig {
+        xml::ParserConfig::new()
+            .trim_whitespace(self.trim_whitespace)
+            .whitespace_to_characters(self.whitespace_to_characters)
+            .replace_unknown_entity_references(self.replace_unknown_entity_references)
+            .ignore_comments(self.ignore_comments)
+    }
+
+    fn check_doctype(&self, doctype: Option<&str>) -> crate::Result<()> {
+        if let Some(doctype) = doctype {
+            if self.forbid_dtd {
+                return Err(crate::Error::SecurityViolation("DTD not allowed"));
+            }
+            if self.forbid_external_entities && EXTERNAL_ENTITY_RE.is_match(doctype) {
+                return Err(crate::Error::SecurityViolation("external entities not allowed"));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_text_length(&self, text: &str) -> crate::Result<()> {
+        match self.max_text_length {
+            Some(max) if text.len() > max => Err(crate::Error::SecurityViolation("text content exceeds max_text_length")),
+            _ => Ok(())
+        }
+    }
+
+    fn check_depth(&self, depth: u64) -> crate::Result<()> {
+        match self.max_depth {
+            Some(max) if depth > max as u64 => Err(crate::Error::SecurityViolation("element nesting exceeds max_depth")),
+            _ => Ok(())
+        }
+    }
+
+    fn check_attributes(&self, attrs: &[xml::attribute::OwnedAttribute]) -> crate::Result<()> {
+        match self.max_attributes {
+            Some(max) if attrs.len() > max => Err(crate::Error::SecurityViolation("attribute count exceeds max_attributes")),
+            _ => Ok(())
+        }
+    }
+}
+
 pub fn from_str<'a, T: Deserialize<'a>>(s: &'a str) -> crate::Result<T> {
-    let conf = xml::ParserConfig::new()
-        .trim_whitespace(true)
-        .whitespace_to_characters(true)
-        .replace_unknown_entity_references(true);
-    let mut event_reader = xml::reader::EventReader::new_with_config(s.as_bytes(), conf);
+    from_str_with_config(s, DeserializerConfig::default())
+}
+
+pub fn from_str_with_config<'a, T: Deserialize<'a>>(s: &'a str, config: DeserializerConfig) -> crate::Result<T> {
+    from_bytes(s.as_bytes(), config)
+}
+
+pub fn from_string<'a, T: Deserialize<'a>>(s: String) -> crate::Result<T> {
+    from_string_with_config(s, DeserializerConfig::default())
+}
+
+pub fn from_string_with_config<'a, T: Deserialize<'a>>(s: String, config: DeserializerConfig) -> crate::Result<T> {
+    from_bytes(s.as_bytes(), config)
+}
+
+pub fn from_reader<R: std::io::Read, T: de::DeserializeOwned>(reader: R) -> crate::Result<T> {
+    from_reader_with_config(reader, DeserializerConfig::default())
+}
+
+pub fn from_reader_with_config<R: std::io::Read, T: de::DeserializeOwned>(mut reader: R, config: DeserializerConfig) -> crate::Result<T> {
+    let mut s = String::new();
+    reader.read_to_string(&mut s)?;
+    from_str_with_config(&s, config)
+}
+
+fn from_bytes<'a, T: Deserialize<'a>>(s: &[u8], config: DeserializerConfig) -> crate::Result<T> {
+    // Newer xml-rs releases reject anything before the XML declaration, so skip leading whitespace
+    let start = s.iter().position(|c| !c.is_ascii_whitespace()).unwrap_or(s.len());
+    let mut event_reader = xml::reader::EventReader::new_with_config(&s[start..], config.parser_config());
     match event_reader.next()? {
         xml::reader::XmlEvent::StartDocument {
             version,
@@ -44,48 +210,49 @@ pub fn from_str<'a, T: Deserialize<'a>>(s: &'a str) -> crate::Result<T> {
         }
         _ => return Err(crate::Error::ExpectedElement)
     }
-    let mut deserializer = Deserializer {
-        reader: new_reader(event_reader),
-        depth: 0,
-        is_map_value: false,
-        is_greedy: true,
-        is_value: false,
-        reset_peek_offset: 0,
-    };
+    // Any DTD comes before the root element, so read up to it before checking the DTD
+    let mut prologue = vec![];
+    loop {
+        let event = event_reader.next()?;
+        let done = matches!(event, xml::reader::XmlEvent::StartElement { .. } | xml::reader::XmlEvent::EndDocument);
+        prologue.push(Ok(event));
+        if done {
+            break;
+        }
+    }
+    config.check_doctype(event_reader.doctype())?;
+    let mut deserializer = Deserializer::new(new_reader(prologue.into_iter().chain(event_reader)))
+        .with_config(config);
     let t = T::deserialize(&mut deserializer)?;
     Ok(t)
 }
 
-pub fn from_string<'a, T: Deserialize<'a>>(s: String) -> crate::Result<T> {
-    let conf = xml::ParserConfig::new()
-        .trim_whitespace(true)
-        .whitespace_to_characters(true)
-        .replace_unknown_entity_references(true);
-    let mut event_reader = xml::reader::EventReader::new_with_config(s.as_bytes(), conf);
+/// Deserialize an XML fragment, which may have text and any number of elements at the top level
+pub fn from_fragment_str<'a, T: Deserialize<'a>>(s: &'a str) -> crate::Result<T> {
+    // Wrap the fragment in an element, so it can be parsed as a document
+    let wrapped = format!("<fragment>{}</fragment>", s);
+    let mut event_reader = xml::reader::EventReader::new_with_config(
+        wrapped.as_bytes(), DeserializerConfig::default().parser_config().allow_multiple_root_elements(false),
+    );
     match event_reader.next()? {
-        xml::reader::XmlEvent::StartDocument {
-            version,
-            encoding,
-            standalone
-        } => {
-            trace!("start_document({:?}, {:?}, {:?})", version, encoding, standalone);
-        }
+        xml::reader::XmlEvent::StartDocument { .. } => {}
         _ => return Err(crate::Error::ExpectedElement)
     }
-    let mut deserializer = Deserializer {
-        reader: new_reader(event_reader),
-        depth: 0,
-        is_map_value: false,
-        is_greedy: true,
-        is_value: false,
-        reset_peek_offset: 0,
-    };
+    match event_reader.next()? {
+        xml::reader::XmlEvent::StartElement { .. } => {}
+        _ => return Err(crate::Error::ExpectedElement)
+    }
+    let mut deserializer = Deserializer::new(new_reader(event_reader)).with_depth(1);
     let t = T::deserialize(&mut deserializer)?;
+    // The fragment could close the wrapper itself, so check nothing follows it
+    for event in deserializer.reader {
+        event?;
+    }
     Ok(t)
 }
 
 pub fn from_events<'a, T: Deserialize<'a>>(s: &[xml::reader::Result<xml::reader::XmlEvent>]) -> crate::Result<T> {
-    let mut reader = new_reader(s.into_iter().map(|r| r.to_owned()));
+    let mut reader = new_reader(s.iter().map(|r| r.to_owned()));
     if let Ok(xml::reader::XmlEvent::StartDocument { .. }) = reader.peek().ok_or(crate::Error::ExpectedElement)? {
         match reader.next() {
             Some(Ok(xml::reader::XmlEvent::StartDocument {
@@ -99,19 +266,168 @@ pub fn from_events<'a, T: Deserialize<'a>>(s: &[xml::reader::Result<xml::reader:
         }
     }
     reader.reset_peek();
-    let mut deserializer = Deserializer {
-        reader,
-        depth: 0,
-        is_map_value: false,
-        is_greedy: true,
-        is_value: false,
-        reset_peek_offset: 0,
-    };
+    let mut deserializer = Deserializer::new(reader);
     let t = T::deserialize(&mut deserializer)?;
     Ok(t)
 }
 
+/// Deserialize each top-level element of a stream of XML in turn, for protocols (such as XMPP)
+/// that send a series of elements rather than a single document.
+///
+/// Each element is deserialized as if it were a document of its own.
+pub struct DeserializerIter<T, R: std::io::Read> {
+    reader: xml::reader::EventReader<R>,
+    config: DeserializerConfig,
+    done: bool,
+    _marker: std::marker::PhantomData<T>,
+}
+
+impl<T, R: std::io::Read> DeserializerIter<T, R> {
+    pub fn new(reader: R) -> Self {
+        let config = DeserializerConfig::default();
+        Self {
+            reader: xml::reader::EventReader::new_with_config(reader, config.parser_config()),
+            config,
+            done: false,
+            _marker: std::marker::PhantomData,
+        }
+    }
+
+    /// Read the events making up the next top-level element, if there is one
+    fn next_element(&mut self) -> crate::Result<Option<Vec<XmlRes>>> {
+        loop {
+            match self.reader.next()? {
+                event @ xml::reader::XmlEvent::StartElement { .. } => {
+                    self.config.check_doctype(self.reader.doctype())?;
+                    let mut events = vec![Ok(event)];
+                    let mut depth = 1;
+                    while depth > 0 {
+                        let event = self.reader.next()?;
+                        match event {
+                            xml::reader::XmlEvent::StartElement { .. } => depth += 1,
+                            xml::reader::XmlEvent::EndElement { .. } => depth -= 1,
+                            xml::reader::XmlEvent::EndDocument => return Err(crate::Error::ExpectedElement),
+                            _ => {}
+                        }
+                        events.push(Ok(event));
+                    }
+                    return Ok(Some(events));
+                }
+                xml::reader::XmlEvent::EndDocument => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<T: for<'de> Deserialize<'de>, R: std::io::Read> Iterator for DeserializerIter<T, R> {
+    type Item = crate::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_element() {
+            Ok(Some(events)) => {
+                let mut deserializer = Deserializer::new(new_reader(events))
+                    .with_config(self.config.clone());
+                Some(T::deserialize(&mut deserializer))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                // The reader can't recover from an error, so don't try to read any further
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<R: std::io::Read> Deserializer<xml::reader::Events<R>> {
+    /// Create a deserializer reading from an xml-rs event reader.
+    ///
+    /// The reader should already be positioned past the `StartDocument` event, at the point
+    /// deserialization is to start.
+    pub fn from_event_reader(reader: xml::reader::EventReader<R>) -> Deserializer<impl Iterator<Item=XmlRes>> {
+        Deserializer::new(new_reader(reader))
+    }
+}
+
 impl<I: Iterator<Item=XmlRes>> Deserializer<I> {
+    /// Create a deserializer reading from a peekable stream of xml-rs events.
+    ///
+    /// Insignificant whitespace is dropped from the stream, so the reader's parser needn't be
+    /// configured with `whitespace_to_characters`.
+    pub fn new(reader: itertools::MultiPeek<I>) -> Self {
+        let is_significant: fn(&XmlRes) -> bool = |e| !matches!(e, Ok(xml::reader::XmlEvent::Whitespace(_)));
+        Self {
+            reader: itertools::multipeek(reader.filter(is_significant)),
+            depth: 0,
+            is_map_value: false,
+            is_greedy: true,
+            is_value: false,
+            is_raw_value: false,
+            reset_peek_offset: 0,
+            config: DeserializerConfig::default(),
+        }
+    }
+
+    /// Set the element depth the deserializer starts at, for use when it is created part way
+    /// through a document.
+    pub fn with_depth(mut self, depth: u64) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Set the options the deserializer uses.
+    ///
+    /// Only the options affecting how values are read apply here, as parsing options need to be
+    /// set on the xml-rs reader itself.
+    pub fn with_config(mut self, config: DeserializerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// The current element nesting depth.
+    ///
+    /// This starts at 0 (or the value given to `with_depth`) before the first `StartElement`
+    /// event, goes up by 1 for each `StartElement` consumed, and down by 1 for each `EndElement`.
+    /// Deserializing an element's value consumes both its `StartElement` and `EndElement`, so the
+    /// depth after deserializing a value is the same as before it.
+    pub fn depth(&self) -> u64 {
+        self.depth
+    }
+
+    /// Consume the next element, along with everything within it.
+    ///
+    /// The element's `StartElement` event must not have been consumed yet; anything else next
+    /// (other than whitespace and comments) is an error, and is left to be read.
+    pub fn skip_element(&mut self) -> crate::Result<()> {
+        trace!("skip_element()");
+        loop {
+            match self.with_peek(|e| match e {
+                xml::reader::XmlEvent::StartElement { .. } => Some(true),
+                xml::reader::XmlEvent::Whitespace(_) | xml::reader::XmlEvent::Comment(_) => None,
+                _ => Some(false)
+            })? {
+                Some(true) => break,
+                Some(false) => return Err(crate::Error::ExpectedElement),
+                None => {
+                    self.next()?;
+                }
+            }
+        }
+        let depth = self.depth;
+        self.next()?;
+        while self.depth > depth {
+            self.next_significant()?;
+        }
+        Ok(())
+    }
+
     fn set_map_value(&mut self) {
         trace!("set_map_value()");
         self.is_map_value = true;
@@ -131,6 +447,15 @@ impl<I: Iterator<Item=XmlRes>> Deserializer<I> {
         std::mem::replace(&mut self.is_value, false)
     }
 
+    fn set_raw_value(&mut self) {
+        trace!("set_raw_value()");
+        self.is_raw_value = true;
+    }
+
+    fn unset_raw_value(&mut self) -> bool {
+        std::mem::replace(&mut self.is_raw_value, false)
+    }
+
     fn set_not_greedy(&mut self) {
         trace!("set_not_greedy()");
         self.is_greedy = false;
@@ -142,6 +467,11 @@ impl<I: Iterator<Item=XmlRes>> Deserializer<I> {
         std::mem::replace(&mut self.is_greedy, true)
     }
 
+    /// Look at the next event, without consuming it.
+    ///
+    /// Each call moves on to the event after the last one peeked, until `reset_peek` (or consuming
+    /// an event) moves back to the next unconsumed event. Prefer `with_peek` for looking at just
+    /// the next event, and only use this directly when looking further ahead.
     fn peek(&mut self) -> crate::Result<&xml::reader::XmlEvent> {
         let next = match match self.reader.peek() {
             Some(n) => n,
@@ -154,6 +484,18 @@ impl<I: Iterator<Item=XmlRes>> Deserializer<I> {
         Ok(next)
     }
 
+    /// Look at the next event with `f`, leaving the event to be peeked or consumed again
+    fn with_peek<T, F: FnOnce(&xml::reader::XmlEvent) -> T>(&mut self, f: F) -> crate::Result<T> {
+        // Comments are never significant, so get them out of the way unless already peeking ahead
+        while self.reset_peek_offset == 0 && matches!(self.peek()?, xml::reader::XmlEvent::Comment(_)) {
+            self.next()?;
+        }
+        self.reset_peek();
+        let ret = f(self.peek()?);
+        self.reset_peek();
+        Ok(ret)
+    }
+
     fn reset_peek(&mut self) {
         self.reader.reset_peek();
         for _ in 0..self.reset_peek_offset {
@@ -167,11 +509,14 @@ impl<I: Iterator<Item=XmlRes>> Deserializer<I> {
             None => return Err(crate::Error::ExpectedElement)
         }?;
         match next {
-            xml::reader::XmlEvent::StartElement { .. } => {
+            xml::reader::XmlEvent::StartElement { ref attributes, .. } => {
+                self.config.check_attributes(attributes)?;
                 self.depth += 1;
+                self.config.check_depth(self.depth)?;
             }
             xml::reader::XmlEvent::EndElement { .. } => {
-                self.depth -= 1;
+                // More closing than opening tags means the events aren't well-formed
+                self.depth = self.depth.checked_sub(1).ok_or(crate::Error::ExpectedElement)?;
             }
             _ => {}
         }
@@ -179,13 +524,23 @@ impl<I: Iterator<Item=XmlRes>> Deserializer<I> {
         Ok(next)
     }
 
+    /// The next event, skipping over any insignificant whitespace and comments
+    fn next_significant(&mut self) -> crate::Result<xml::reader::XmlEvent> {
+        loop {
+            match self.next()? {
+                xml::reader::XmlEvent::Whitespace(_) | xml::reader::XmlEvent::Comment(_) => continue,
+                next => return Ok(next)
+            }
+        }
+    }
+
     fn read_inner_value<T, F: FnOnce(&mut Self) -> crate::Result<T>>(&mut self, f: F) -> crate::Result<T> {
         trace!("read_inner_value()");
         let old_greedy = self.is_greedy;
         let ret = if self.unset_map_value() {
-            match self.next()? {
+            match self.next_significant()? {
                 xml::reader::XmlEvent::StartElement { name, .. } => {
-                    let result = f(self)?;
+                    let result = f(self).map_err(|e| e.in_element(&name))?;
                     self.expect_end_element(name)?;
                     Ok(result)
                 }
@@ -202,9 +557,9 @@ impl<I: Iterator<Item=XmlRes>> Deserializer<I> {
         trace!("read_inner_value()");
         let old_greedy = self.is_greedy;
         let ret = if self.unset_map_value() {
-            match self.next()? {
+            match self.next_significant()? {
                 xml::reader::XmlEvent::StartElement { name, attributes, .. } => {
-                    let result = f(self, attributes)?;
+                    let result = f(self, attributes).map_err(|e| e.in_element(&name))?;
                     self.expect_end_element(name)?;
                     Ok(result)
                 }
@@ -219,7 +574,7 @@ impl<I: Iterator<Item=XmlRes>> Deserializer<I> {
 
     fn expect_end_element(&mut self, old_name: xml::name::OwnedName) -> crate::Result<()> {
         trace!("expect_end_element({:?})", old_name);
-        match self.next()? {
+        match self.next_significant()? {
             xml::reader::XmlEvent::EndElement { name } => {
                 if name == old_name {
                     Ok(())
@@ -231,17 +586,51 @@ impl<I: Iterator<Item=XmlRes>> Deserializer<I> {
         }
     }
 
+    /// Reads all directly following text and CDATA events as one string, as xml-rs may split a
+    /// single run of text into several events (e.g. around CDATA sections).
+    fn read_text_coalesced(&mut self) -> crate::Result<String> {
+        let mut output = String::new();
+        while self.with_peek(|e| matches!(e, xml::reader::XmlEvent::CData(_) | xml::reader::XmlEvent::Characters(_)))? {
+            match self.next_significant()? {
+                xml::reader::XmlEvent::CData(s) | xml::reader::XmlEvent::Characters(s) => {
+                    output.push_str(&s);
+                    self.config.check_text_length(&output)?;
+                }
+                _ => unreachable!()
+            }
+        }
+        Ok(output)
+    }
+
+    /// Read anything, as for `deserialize_any`, but keep every run of text in mixed content, and
+    /// which of them were CDATA, for `XmlValue`
+    fn read_xml_value<'de, V: serde::de::Visitor<'de>>(&mut self, visitor: V) -> crate::Result<V::Value> {
+        trace!("read_xml_value()");
+        if !self.is_map_value && self.with_peek(|e| matches!(e, xml::reader::XmlEvent::CData(_) | xml::reader::XmlEvent::Characters(_)))? {
+            return de::Deserializer::deserialize_any(self, visitor);
+        }
+        self.read_inner_value_attrs(|this, attrs| {
+            let mut map = Map::new(this, attrs, &[]);
+            map.all_text = true;
+            visitor.visit_map(map)
+        })
+    }
+
     fn parse_string(&mut self) -> crate::Result<String> {
         trace!("prase_string()");
+        let raw = self.unset_raw_value();
         self.read_inner_value(|this| {
-            match this.peek()? {
-                xml::reader::XmlEvent::EndElement {
-                    ..
-                } => return Ok(String::new()),
-                _ => {}
+            if this.with_peek(|e| matches!(e, xml::reader::XmlEvent::EndElement { .. }))? {
+                return Ok(String::new());
             }
-            match this.next()? {
-                xml::reader::XmlEvent::CData(s) | xml::reader::XmlEvent::Characters(s) => {
+            match this.next_significant()? {
+                xml::reader::XmlEvent::CData(mut s) | xml::reader::XmlEvent::Characters(mut s) => {
+                    s.push_str(&this.read_text_coalesced()?);
+                    this.config.check_text_length(&s)?;
+                    // Raw values are markup, so text in them has to stay escaped to be written back out
+                    if raw {
+                        s = xml::escape::escape_str_pcdata(&s).into_owned();
+                    }
                     Ok(s)
                 }
                 xml::reader::XmlEvent::StartElement {
@@ -258,26 +647,37 @@ impl<I: Iterator<Item=XmlRes>> Deserializer<I> {
                         .keep_element_names_stack(false)
                         .pad_self_closing(false);
                     let mut writer = conf.create_writer(&mut output);
-                    writer.write(xml::writer::XmlEvent::StartElement {
-                        name: name.borrow(),
-                        attributes: attributes.iter().map(|a| a.borrow()).collect(),
-                        namespace: std::borrow::Cow::Borrowed(&namespace),
-                    }).unwrap();
+                    // Explicitly re-declare every namespace in scope on the first element, as the
+                    // declarations may have been made on an ancestor we aren't reproducing
+                    let mut elm = xml::writer::XmlEvent::start_element(name.borrow());
+                    for (prefix, uri) in &namespace {
+                        match prefix {
+                            xml::namespace::NS_XML_PREFIX | xml::namespace::NS_XMLNS_PREFIX => {}
+                            xml::namespace::NS_NO_PREFIX => if !uri.is_empty() {
+                                elm = elm.default_ns(uri);
+                            },
+                            _ => elm = elm.ns(prefix, uri),
+                        }
+                    }
+                    for attr in &attributes {
+                        elm = elm.attr(attr.name.borrow(), &attr.value);
+                    }
+                    writer.write(elm)?;
                     let depth = this.depth - 1;
                     loop {
-                        let event = this.next()?;
+                        let event = this.next_significant()?;
                         trace!("{:?}; {}; {}", event, this.depth, depth);
                         if this.depth == depth {
                             break;
                         }
                         if let Some(e) = event.as_writer_event() {
                             trace!("{:?}; {}; {}", event, this.depth, depth);
-                            writer.write(e).unwrap();
+                            writer.write(e)?;
                         }
                     }
                     writer.write(xml::writer::XmlEvent::EndElement {
                         name: Some(name.borrow())
-                    }).unwrap();
+                    })?;
                     Ok(String::from_utf8(output).unwrap())
                 }
                 _ => Err(crate::Error::ExpectedString)
@@ -285,6 +685,25 @@ impl<I: Iterator<Item=XmlRes>> Deserializer<I> {
         })
     }
 
+    /// Peek ahead to see if the next element contains only text, and has no attributes
+    fn peek_text_element(&mut self) -> crate::Result<bool> {
+        match self.peek()? {
+            xml::reader::XmlEvent::StartElement { attributes, .. } => {
+                if !attributes.iter().all(|a| is_namespace_declaration(&a.name)) {
+                    return Ok(false);
+                }
+            }
+            _ => return Ok(false)
+        }
+        loop {
+            match self.peek()? {
+                xml::reader::XmlEvent::CData(_) | xml::reader::XmlEvent::Characters(_) => {}
+                xml::reader::XmlEvent::EndElement { .. } => return Ok(true),
+                _ => return Ok(false)
+            }
+        }
+    }
+
     fn parse_bool(&mut self) -> crate::Result<bool> {
         let s = self.parse_string()?;
         match s.to_lowercase().as_str() {
@@ -301,21 +720,40 @@ impl<I: Iterator<Item=XmlRes>> Deserializer<I> {
             Err(_) => Err(crate::Error::ExpectedInt)
         }
     }
+
+    /// Parse a float, including the XSD special values `INF`, `-INF` and `NaN`; Rust's parsing
+    /// accepts these in any case
+    fn parse_float<T: std::str::FromStr>(&mut self) -> crate::Result<T> {
+        let s = self.parse_string()?;
+        match s.parse::<T>() {
+            Ok(f) => Ok(f),
+            Err(_) => Err(crate::Error::ExpectedFloat)
+        }
+    }
 }
 
-impl<'de, 'a, I: Iterator<Item=XmlRes>> de::Deserializer<'de> for &'a mut Deserializer<I> {
+impl<'de, I: Iterator<Item=XmlRes>> de::Deserializer<'de> for &mut Deserializer<I> {
     type Error = crate::Error;
 
     fn deserialize_any<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
         trace!("deserialize_any()");
-        if let xml::reader::XmlEvent::CData(_) | xml::reader::XmlEvent::Characters(_) = self.peek()? {
-            let s = match self.next()? {
+        if self.is_map_value {
+            // Elements with nothing but text in them are presented as plain strings
+            let is_text = self.peek_text_element()?;
+            self.reset_peek();
+            if is_text {
+                return visitor.visit_string(self.parse_string()?);
+            }
+        }
+        if self.with_peek(|e| matches!(e, xml::reader::XmlEvent::CData(_) | xml::reader::XmlEvent::Characters(_)))? {
+            let mut s = match self.next_significant()? {
                 xml::reader::XmlEvent::CData(s) | xml::reader::XmlEvent::Characters(s) => s,
                 _ => unreachable!()
             };
+            s.push_str(&self.read_text_coalesced()?);
+            self.config.check_text_length(&s)?;
             visitor.visit_string(s)
         } else {
-            self.reset_peek();
             self.read_inner_value_attrs(|this, attrs| {
                 visitor.visit_map(Map::new(this, attrs, &[]))
             })
@@ -361,11 +799,11 @@ impl<'de, 'a, I: Iterator<Item=XmlRes>> de::Deserializer<'de> for &'a mut Deseri
     }
 
     fn deserialize_f32<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
-        visitor.visit_u64(self.parse_int()?)
+        visitor.visit_f32(self.parse_float()?)
     }
 
     fn deserialize_f64<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
-        visitor.visit_u64(self.parse_int()?)
+        visitor.visit_f64(self.parse_float()?)
     }
 
     fn deserialize_char<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
@@ -390,14 +828,19 @@ impl<'de, 'a, I: Iterator<Item=XmlRes>> de::Deserializer<'de> for &'a mut Deseri
         self.deserialize_str(visitor)
     }
 
-    fn deserialize_bytes<V: serde::de::Visitor<'de>>(self, _visitor: V) -> crate::Result<V::Value> {
+    fn deserialize_bytes<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
         trace!("deserialize_bytes()");
-        Err(crate::Error::Unsupported)
+        self.deserialize_byte_buf(visitor)
     }
 
-    fn deserialize_byte_buf<V: serde::de::Visitor<'de>>(self, _visitor: V) -> crate::Result<V::Value> {
+    /// Bytes are read as hex, as written by the serializer
+    fn deserialize_byte_buf<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
         trace!("deserialize_byte_buf()");
-        Err(crate::Error::Unsupported)
+        let s = self.parse_string()?;
+        match hex::decode(&s) {
+            Ok(v) => visitor.visit_byte_buf(v),
+            Err(_) => Err(de::Error::invalid_value(de::Unexpected::Str(&s), &"hex encoded bytes"))
+        }
     }
 
     fn deserialize_option<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
@@ -411,10 +854,15 @@ impl<'de, 'a, I: Iterator<Item=XmlRes>> de::Deserializer<'de> for &'a mut Deseri
             }
         }
         if let xml::reader::XmlEvent::EndElement { .. } = self.peek()? {
+            // An empty element, rather than a missing one
+            if self.is_map_value && !self.config.empty_string_as_none {
+                self.reset_peek();
+                return visitor.visit_some(self);
+            }
             if self.unset_map_value() {
-                self.next()?;
+                self.next_significant()?;
             }
-            self.next()?;
+            self.next_significant()?;
             visitor.visit_none()
         } else {
             self.reset_peek();
@@ -423,22 +871,27 @@ impl<'de, 'a, I: Iterator<Item=XmlRes>> de::Deserializer<'de> for &'a mut Deseri
     }
 
     fn deserialize_unit<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
+        // Consume the (empty) element the unit is written as
+        self.read_inner_value(|_| Ok(()))?;
         visitor.visit_unit()
     }
 
     fn deserialize_unit_struct<V: serde::de::Visitor<'de>>(self, name: &'static str, visitor: V) -> crate::Result<V::Value> {
         trace!("deserialize_unit_struct({:?})", name);
-        visitor.visit_unit()
+        self.deserialize_unit(visitor)
     }
 
     fn deserialize_newtype_struct<V: serde::de::Visitor<'de>>(self, name: &'static str, visitor: V) -> crate::Result<V::Value> {
         trace!("deserialize_newtype_struct({:?})", name);
+        if name == crate::value::XML_VALUE_NAME {
+            return self.read_xml_value(visitor);
+        }
         visitor.visit_newtype_struct(self)
     }
 
-    fn deserialize_seq<V: serde::de::Visitor<'de>>(mut self, visitor: V) -> crate::Result<V::Value> {
+    fn deserialize_seq<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
         trace!("deserialize_seq()");
-        visitor.visit_seq(Seq::new(&mut self)?)
+        visitor.visit_seq(Seq::new(self)?)
     }
 
     fn deserialize_tuple<V: serde::de::Visitor<'de>>(self, len: usize, visitor: V) -> crate::Result<V::Value> {
@@ -484,12 +937,10 @@ impl<'de, 'a, I: Iterator<Item=XmlRes>> de::Deserializer<'de> for &'a mut Deseri
     fn deserialize_ignored_any<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
         trace!("deserialize_ignored_any()");
         if self.is_greedy {
-            let depth = self.depth;
-            loop {
-                self.next()?;
-                if self.depth == depth {
-                    break;
-                }
+            if self.with_peek(|e| matches!(e, xml::reader::XmlEvent::StartElement { .. }))? {
+                self.skip_element()?;
+            } else {
+                self.next_significant()?;
             }
         } else {
             let mut depth = 0;
@@ -522,14 +973,14 @@ struct Seq<'a, I: Iterator<Item=XmlRes>> {
 impl<'a, I: Iterator<Item=XmlRes>> Seq<'a, I> {
     fn new(de: &'a mut Deserializer<I>) -> crate::Result<Self> {
         let name = if de.unset_map_value() {
-            let val = match de.peek()? {
+            de.with_peek(|e| match e {
                 xml::reader::XmlEvent::StartElement { name, .. } => {
-                    Some(name.clone())
+                    Ok(Some(name.clone()))
                 }
-                _ => return Err(crate::Error::ExpectedElement)
-            };
-            de.reset_peek();
-            val
+                // Nothing to read, so an empty sequence
+                xml::reader::XmlEvent::EndElement { .. } | xml::reader::XmlEvent::EndDocument => Ok(None),
+                _ => Err(crate::Error::ExpectedElement)
+            })??
         } else {
             None
         };
@@ -545,14 +996,15 @@ impl<'de, 'a, I: Iterator<Item=XmlRes>> de::SeqAccess<'de> for Seq<'a, I> {
 
     fn next_element_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> crate::Result<Option<T::Value>> {
         trace!("next_element_seed()");
-        let more = match (self.de.peek()?, self.expected_name.as_ref()) {
+        let expected_name = self.expected_name.as_ref();
+        let more = self.de.with_peek(|e| match (e, expected_name) {
             (xml::reader::XmlEvent::StartElement { ref name, .. }, Some(expected_name)) => {
-                name == expected_name
+                // Prefixes are only aliases, so don't compare them
+                name.local_name == expected_name.local_name && name.namespace == expected_name.namespace
             }
-            (xml::reader::XmlEvent::EndElement { .. }, None) | (_, Some(_)) | (xml::reader::XmlEvent::EndDocument { .. }, _) => false,
+            (xml::reader::XmlEvent::EndElement { .. }, None) | (_, Some(_)) | (xml::reader::XmlEvent::EndDocument, _) => false,
             (_, None) => true,
-        };
-        self.de.reset_peek();
+        })?;
         if more {
             if self.expected_name.is_some() {
                 self.de.set_map_value();
@@ -569,6 +1021,9 @@ struct Fields {
     inner_value: bool,
     num_value: u64,
     value_used: u64,
+    attr_map: bool,
+    list: bool,
+    default_namespace: Option<String>,
 }
 
 #[derive(Clone)]
@@ -581,12 +1036,9 @@ struct Field {
 
 impl From<&&'static str> for Field {
     fn from(from: &&'static str) -> Self {
-        let mut attr = false;
-        let name = if from.starts_with("$attr:") {
-            attr = true;
-            &from[6..]
-        } else {
-            from
+        let (name, attr) = match from.strip_prefix("$attr:") {
+            Some(name) => (name, true),
+            None => (*from, false),
         };
 
         let Tag{
@@ -610,9 +1062,11 @@ impl From<&'static [&'static str]> for Fields {
         use std::sync::Mutex;
         use std::collections::btree_map::{BTreeMap,Entry};
 
+        type FieldCache = BTreeMap<usize, (&'static [Field], u64)>;
+
         let (fields, num_value) = {
             // Make a single global BTreeMap to act as a cache
-            static CACHE: OnceCell<Mutex<BTreeMap<usize, (&'static [Field], u64)>>> = OnceCell::new();
+            static CACHE: OnceCell<Mutex<FieldCache>> = OnceCell::new();
             let mut cache = CACHE.get_or_init(|| {
                 Mutex::new(BTreeMap::new())
             }).lock().unwrap();
@@ -628,7 +1082,7 @@ impl From<&'static [&'static str]> for Fields {
                     let fields = Box::leak(fields.into_boxed_slice());
 
                     // Count how many $value fields we have
-                    let num_value = from.iter().filter(|f| f.starts_with(&"$value")).count() as u64;
+                    let num_value = from.iter().filter(|f| f.starts_with("$value")).count() as u64;
 
                     // Add it to the cache
                     *e.insert((fields, num_value))
@@ -644,73 +1098,153 @@ impl From<&'static [&'static str]> for Fields {
         Fields {
             fields,
             inner_value: num_value >= 1,
-            num_value: num_value,
+            num_value,
             value_used: 0,
+            attr_map: fields.iter().any(|f| f.name == "$attr_map"),
+            list: fields.iter().any(|f| f.name == "$list"),
+            default_namespace: None,
         }
     }
 }
 
 impl Fields {
     fn match_field(&mut self, name: &xml::name::OwnedName) -> Cow<'static, str> {
+        let in_default_namespace = name.namespace.is_some() && name.namespace == self.default_namespace;
         for field in self.fields.iter() {
-            if field.local_name == name.local_name && field.namespace == name.namespace.as_deref() && !field.attr {
+            let namespace_matches = field.namespace == name.namespace.as_deref() ||
+                (field.namespace.is_none() && in_default_namespace);
+            if field.local_name == name.local_name && namespace_matches && !field.attr {
                 trace!("match_field({:?}) -> {:?}", name, field.name);
                 return field.name.into();
             }
         }
         let name_str = if self.inner_value && self.value_used < self.num_value {
             self.value_used += 1;
-            if self.num_value == 1 {
-                "$value".to_string()
-            } else {
-                format!("$value{}", self.value_used)
-            }
+            self.value_field(self.value_used as usize - 1)
         } else {
-            match &name.namespace {
-                Some(n) => format!("{{{}}}{}", n, name.local_name),
-                None => name.local_name.clone()
-            }
+            element_key(name).into()
         };
         trace!("match_field({:?}) -> {:?}", name, name_str);
-        name_str.into()
+        name_str
     }
 
-    fn match_attr(&self, name: &xml::name::OwnedName) -> Cow<'static, str> {
-        for field in self.fields.iter() {
-            if field.local_name == name.local_name && field.namespace == name.namespace.as_deref() && field.attr {
-                let name_str = format!("$attr:{}", field.name);
-                trace!("match_attr({:?}) -> {:?}", name, name_str);
-                return name_str.into();
-            }
+    /// The name of the nth `$value` field (such as `$value` or `$valueRaw`), for the nth child
+    /// element not matching any other field
+    fn value_field(&self, n: usize) -> Cow<'static, str> {
+        match self.fields.iter().filter(|f| f.name.starts_with("$value")).nth(n) {
+            Some(field) => field.name.into(),
+            None => "$value".into()
         }
-        let name_str = match &name.namespace {
-            Some(n) => format!("{{{}}}{}", n, name.local_name),
-            None => name.local_name.clone()
+    }
+
+    fn find_attr(&self, name: &xml::name::OwnedName) -> Option<&Field> {
+        self.fields.iter().find(|field| {
+            field.local_name == name.local_name && field.namespace == name.namespace.as_deref() && field.attr
+        })
+    }
+
+    fn match_attr(&self, name: &xml::name::OwnedName) -> Cow<'static, str> {
+        let name_str = match self.find_attr(name) {
+            Some(field) => format!("$attr:{}", field.name),
+            None => format!("$attr:{}", name_to_tag(name))
         };
-        let name_str = format!("$attr:{}", name_str);
         trace!("match_attr({:?}) -> {:?}", name, name_str);
         name_str.into()
     }
 }
 
+/// Format an element name as a `{namespace}tag-name` map key
+///
+/// The prefix is left out as it is chosen by the document, so keys only depend on the expanded name.
+fn element_key(name: &xml::name::OwnedName) -> String {
+    Tag {
+        n: name.namespace.as_deref(),
+        l: None,
+        p: None,
+        e: &name.local_name,
+    }.to_tag_string()
+}
+
+/// Format a name in the `{namespace}prefix:tag-name` format used for field names
+fn name_to_tag(name: &xml::name::OwnedName) -> String {
+    Tag {
+        n: name.namespace.as_deref(),
+        l: None,
+        p: name.prefix.as_deref(),
+        e: &name.local_name,
+    }.to_tag_string()
+}
+
 struct Map<'a, I: Iterator<Item=XmlRes>> {
     de: &'a mut Deserializer<I>,
     attrs: Vec<xml::attribute::OwnedAttribute>,
+    attr_map: Option<Vec<(String, AttrValueDeserializer)>>,
     fields: Fields,
     next_value: Option<String>,
+    next_attr_map: Option<Vec<(String, AttrValueDeserializer)>>,
     inner_value: bool,
     next_is_value: bool,
+    next_is_raw: bool,
+    next_is_list: bool,
+    /// Every run of text is its own entry, rather than only the first being used for `$value`
+    all_text: bool,
+    next_is_text: bool,
+    text_used: bool,
+    /// A key has been read, and its value not yet
+    key_read: bool,
+}
+
+/// xml-rs reports namespace declarations separately, but events from other sources may still
+/// carry them as plain attributes
+fn is_namespace_declaration(name: &xml::name::OwnedName) -> bool {
+    name.prefix.as_deref() == Some(xml::namespace::NS_XMLNS_PREFIX) ||
+        (name.prefix.is_none() && name.local_name == xml::namespace::NS_XMLNS_PREFIX)
+}
+
+/// Schema instance attributes are instructions to a validator rather than part of the content
+fn is_schema_instance(name: &xml::name::OwnedName) -> bool {
+    name.namespace.as_deref() == Some(XSI_NAMESPACE) &&
+        matches!(name.local_name.as_str(), "schemaLocation" | "noNamespaceSchemaLocation" | "type")
 }
 
 impl<'a, I: Iterator<Item=XmlRes>> Map<'a, I> {
-    fn new(de: &'a mut Deserializer<I>, attrs: Vec<xml::attribute::OwnedAttribute>, fields: &'static [&'static str]) -> Self {
+    fn new(de: &'a mut Deserializer<I>, mut attrs: Vec<xml::attribute::OwnedAttribute>, fields: &'static [&'static str]) -> Self {
+        let mut fields: Fields = fields.into();
+        fields.default_namespace = de.config.default_namespace.clone();
+        // Schema instance attributes are only kept if a field asks for them
+        attrs.retain(|a| {
+            !is_namespace_declaration(&a.name) && (!is_schema_instance(&a.name) || fields.find_attr(&a.name).is_some())
+        });
+        // Attributes are popped off the end, so reverse them to keep the document order
+        attrs.reverse();
+        // Attributes not claimed by any other field are collected into the `$attr_map` field
+        let attr_map = if fields.attr_map {
+            let (matched, unmatched): (Vec<_>, Vec<_>) = attrs.into_iter()
+                .partition(|a| fields.find_attr(&a.name).is_some());
+            attrs = matched;
+            let empty_is_none = de.config.empty_attr_is_none;
+            Some(unmatched.into_iter().map(|a| (name_to_tag(&a.name), AttrValueDeserializer {
+                value: a.value,
+                empty_is_none,
+            })).collect())
+        } else {
+            None
+        };
         Self {
             de,
             attrs,
-            fields: fields.into(),
+            attr_map,
+            fields,
             next_value: None,
+            next_attr_map: None,
             inner_value: true,
             next_is_value: false,
+            next_is_raw: false,
+            next_is_list: false,
+            all_text: false,
+            next_is_text: false,
+            text_used: false,
+            key_read: false,
         }
     }
 }
@@ -719,6 +1253,22 @@ impl<'de, 'a, I: Iterator<Item=XmlRes>> de::MapAccess<'de> for Map<'a, I> {
     type Error = crate::Error;
 
     fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> crate::Result<Option<K::Value>> {
+        let key = self.read_key(seed)?;
+        self.key_read = key.is_some();
+        Ok(key)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> crate::Result<V::Value> {
+        // Where to read the value from is only known once its key has been read
+        if !std::mem::replace(&mut self.key_read, false) {
+            return Err(crate::Error::ExpectedElement);
+        }
+        self.read_value(seed)
+    }
+}
+
+impl<'de, 'a, I: Iterator<Item=XmlRes>> Map<'a, I> {
+    fn read_key<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> crate::Result<Option<K::Value>> {
         trace!("next_key_seed(); attrs = {:?}", self.attrs);
         match self.attrs.pop() {
             Some(xml::attribute::OwnedAttribute { name, value }) => {
@@ -727,32 +1277,95 @@ impl<'de, 'a, I: Iterator<Item=XmlRes>> de::MapAccess<'de> for Map<'a, I> {
                 self.next_is_value = false;
                 seed.deserialize(name.as_ref().into_deserializer()).map(Some)
             }
+            None if self.attr_map.is_some() => {
+                self.next_attr_map = self.attr_map.take();
+                self.next_is_value = false;
+                seed.deserialize("$attr_map".into_deserializer()).map(Some)
+            }
             None => {
-                let val = match *self.de.peek()? {
+                let text_used = self.text_used;
+                while self.de.with_peek(|e| match e {
+                    // Comments and processing instructions between elements have no bearing on the map
+                    xml::reader::XmlEvent::Comment(_) | xml::reader::XmlEvent::ProcessingInstruction { .. } => true,
+                    // Only the first run of text in mixed content is used for `$value`
+                    xml::reader::XmlEvent::Characters(_) | xml::reader::XmlEvent::CData(_) => text_used && !self.all_text,
+                    _ => false
+                })? {
+                    self.de.next_significant()?;
+                }
+                let depth = self.de.depth;
+                self.de.with_peek(|e| match e {
                     xml::reader::XmlEvent::StartElement {
                         ref name, ..
                     } => {
                         let name = self.fields.match_field(name);
-                        self.inner_value = name.starts_with(&"$value");
-                        self.next_is_value = name.starts_with(&"$value");
+                        self.inner_value = name.starts_with("$value");
+                        self.next_is_value = name.starts_with("$value");
+                        self.next_is_raw = name == "$valueRaw";
                         seed.deserialize(name.as_ref().into_deserializer()).map(Some)
                     }
+                    xml::reader::XmlEvent::Characters(_) | xml::reader::XmlEvent::CData(_) if self.all_text => {
+                        self.next_is_text = true;
+                        let name = match e {
+                            xml::reader::XmlEvent::CData(_) => crate::value::CDATA_KEY,
+                            _ => "$value",
+                        };
+                        seed.deserialize(name.into_deserializer()).map(Some)
+                    }
+                    xml::reader::XmlEvent::Characters(_) | xml::reader::XmlEvent::CData(_) if self.fields.list => {
+                        self.next_is_list = true;
+                        self.text_used = true;
+                        seed.deserialize("$list".into_deserializer()).map(Some)
+                    }
                     xml::reader::XmlEvent::Characters(_) | xml::reader::XmlEvent::CData(_) => {
                         self.next_is_value = true;
-                        seed.deserialize("$value".into_deserializer()).map(Some)
+                        self.text_used = true;
+                        let name = self.fields.value_field(0);
+                        self.next_is_raw = name == "$valueRaw";
+                        seed.deserialize(name.as_ref().into_deserializer()).map(Some)
                     }
+                    // The document can only end here if we're reading the document itself
+                    xml::reader::XmlEvent::EndDocument if depth > 0 => Err(crate::Error::Message(
+                        "unexpected end of document while reading struct fields".to_string()
+                    )),
                     _ => Ok(None)
-                };
-                self.de.reset_peek();
-                val
+                })?
             }
         }
     }
 
-    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> crate::Result<V::Value> {
+    fn read_value<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> crate::Result<V::Value> {
         trace!("next_value_seed(); next_value = {:?}; next_is_value = {}", self.next_value, self.next_is_value);
+        if let Some(attr_map) = self.next_attr_map.take() {
+            return seed.deserialize(de::value::MapDeserializer::new(attr_map.into_iter()));
+        }
+        if std::mem::replace(&mut self.next_is_text, false) {
+            // Just the one run of text, so CDATA stays apart from the text around it
+            return match self.de.next_significant()? {
+                xml::reader::XmlEvent::CData(s) | xml::reader::XmlEvent::Characters(s) => {
+                    self.de.config.check_text_length(&s)?;
+                    let s: de::value::StringDeserializer<crate::Error> = s.into_deserializer();
+                    seed.deserialize(s)
+                }
+                _ => Err(crate::Error::ExpectedString)
+            };
+        }
+        if std::mem::replace(&mut self.next_is_list, false) {
+            // An xs:list, with each item separated by whitespace
+            let text = self.de.read_text_coalesced()?;
+            let empty_is_none = self.de.config.empty_attr_is_none;
+            return seed.deserialize(de::value::SeqDeserializer::new(text.split_whitespace().map(|v| {
+                AttrValueDeserializer {
+                    value: v.to_string(),
+                    empty_is_none,
+                }
+            })));
+        }
         match self.next_value.take() {
-            Some(val) => seed.deserialize(AttrValueDeserializer(val)),
+            Some(val) => seed.deserialize(AttrValueDeserializer {
+                value: val,
+                empty_is_none: self.de.config.empty_attr_is_none,
+            }),
             None => {
                 if !std::mem::replace(&mut self.inner_value, false) {
                     self.de.set_map_value();
@@ -760,11 +1373,17 @@ impl<'de, 'a, I: Iterator<Item=XmlRes>> de::MapAccess<'de> for Map<'a, I> {
                 if self.next_is_value {
                     self.de.set_is_value();
                 }
+                if std::mem::replace(&mut self.next_is_raw, false) {
+                    self.de.set_raw_value();
+                }
                 let greedy = self.next_is_value && self.fields.fields.len() > 1;
                 if greedy {
                     self.de.set_not_greedy();
                 }
-                let val = seed.deserialize(&mut *self.de)?;
+                let val = seed.deserialize(&mut *self.de);
+                // Only strings are read raw, so don't leave it set for the next one if this wasn't
+                self.de.unset_raw_value();
+                let val = val?;
                 if greedy {
                     self.de.unset_not_greedy();
                     self.de.reset_peek();
@@ -778,13 +1397,18 @@ impl<'de, 'a, I: Iterator<Item=XmlRes>> de::MapAccess<'de> for Map<'a, I> {
 pub struct Enum<'a, I: Iterator<Item=XmlRes>> {
     de: &'a mut Deserializer<I>,
     fields: Fields,
+    /// The element doesn't name any of the variants, so can only be a `#[serde(other)]` variant
+    unknown: bool,
 }
 
 impl<'a, I: Iterator<Item=XmlRes>> Enum<'a, I> {
     pub fn new(de: &'a mut Deserializer<I>, fields: &'static [&'static str]) -> Self {
+        let mut fields: Fields = fields.into();
+        fields.default_namespace = de.config.default_namespace.clone();
         Self {
             de,
-            fields: fields.into(),
+            fields,
+            unknown: false,
         }
     }
 }
@@ -795,24 +1419,25 @@ impl<'de, 'a, I: Iterator<Item=XmlRes>> de::EnumAccess<'de> for Enum<'a, I> {
 
     fn variant_seed<V: de::DeserializeSeed<'de>>(mut self, seed: V) -> crate::Result<(V::Value, Self::Variant)> {
         trace!("variant_seed()");
-        let val = match self.de.peek()? {
+        let (val, is_element) = self.de.with_peek(|e| match e {
             xml::reader::XmlEvent::StartElement {
                 name, ..
             } => {
                 let name_str = self.fields.match_field(name);
-                if !name_str.starts_with(&"$value") {
-                    self.de.set_map_value();
-                }
+                self.unknown = !self.fields.fields.iter().any(|f| f.name == name_str);
+                let is_element = !name_str.starts_with("$value");
                 let name_str: serde::de::value::CowStrDeserializer<crate::Error> = name_str.into_deserializer();
-                Ok(seed.deserialize(name_str)?)
+                Ok((seed.deserialize(name_str)?, is_element))
             }
             xml::reader::XmlEvent::Characters(s) | xml::reader::XmlEvent::CData(s) => {
                 let name: serde::de::value::StrDeserializer<crate::Error> = s.as_str().into_deserializer();
-                Ok(seed.deserialize(name)?)
+                Ok((seed.deserialize(name)?, false))
             }
             _ => Err(crate::Error::ExpectedString)
-        }?;
-        self.de.reset_peek();
+        })??;
+        if is_element {
+            self.de.set_map_value();
+        }
         Ok((val, self))
     }
 }
@@ -823,10 +1448,14 @@ impl<'de, 'a, I: Iterator<Item=XmlRes>> de::VariantAccess<'de> for Enum<'a, I> {
     fn unit_variant(self) -> crate::Result<()> {
         trace!("unit_variant()");
         self.de.unset_map_value();
-        match self.de.next()? {
+        if self.unknown {
+            // Nothing is read from an unknown element, so skip over all of it
+            return self.de.skip_element();
+        }
+        match self.de.next_significant()? {
             xml::reader::XmlEvent::StartElement {
                 name, attributes, ..
-            } => if attributes.is_empty() {
+            } => if attributes.iter().all(|a| is_namespace_declaration(&a.name)) {
                 self.de.expect_end_element(name)
             } else {
                 Err(crate::Error::ExpectedElement)
@@ -854,16 +1483,31 @@ impl<'de, 'a, I: Iterator<Item=XmlRes>> de::VariantAccess<'de> for Enum<'a, I> {
     }
 }
 
-struct AttrValueDeserializer(String);
+struct AttrValueDeserializer {
+    value: String,
+    /// Whether an empty value is deserialized as `None` for an `Option`
+    empty_is_none: bool,
+}
 
 macro_rules! deserialize_type_attr {
     ($deserialize:ident => $visit:ident) => {
+        deserialize_type_attr!($deserialize => $visit, ExpectedInt);
+    };
+    ($deserialize:ident => $visit:ident, $error:ident) => {
         fn $deserialize<V: de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
-            visitor.$visit(match self.0.parse() {
+            visitor.$visit(match self.value.parse() {
                 Ok(v) => v,
-                Err(_) => return Err(crate::Error::ExpectedInt)
+                Err(_) => return Err(crate::Error::$error)
             })
         }
+    };
+}
+
+impl<'de> IntoDeserializer<'de, crate::Error> for AttrValueDeserializer {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
     }
 }
 
@@ -871,7 +1515,7 @@ impl<'de> serde::de::Deserializer<'de> for AttrValueDeserializer {
     type Error = crate::Error;
 
     fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
-        visitor.visit_string(self.0)
+        visitor.visit_string(self.value)
     }
 
     deserialize_type_attr!(deserialize_i8 => visit_i8);
@@ -882,28 +1526,41 @@ impl<'de> serde::de::Deserializer<'de> for AttrValueDeserializer {
     deserialize_type_attr!(deserialize_u16 => visit_u16);
     deserialize_type_attr!(deserialize_u32 => visit_u32);
     deserialize_type_attr!(deserialize_u64 => visit_u64);
-    deserialize_type_attr!(deserialize_f32 => visit_f32);
-    deserialize_type_attr!(deserialize_f64 => visit_f64);
+    deserialize_type_attr!(deserialize_f32 => visit_f32, ExpectedFloat);
+    deserialize_type_attr!(deserialize_f64 => visit_f64, ExpectedFloat);
 
     fn deserialize_enum<V: de::Visitor<'de>>(self, name: &str, variants: &'static [&'static str], visitor: V) -> crate::Result<V::Value> {
         trace!("deserialize_enum({:?}, {:?})", name, variants);
-        visitor.visit_enum(self.0.into_deserializer())
+        visitor.visit_enum(self.value.into_deserializer())
     }
 
     fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
-        visitor.visit_some(self)
+        if self.empty_is_none && self.value.is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
     }
 
     fn deserialize_bool<V: de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
-        match self.0.to_lowercase().as_str() {
+        match self.value.to_lowercase().as_str() {
             "true" | "1" | "y" => visitor.visit_bool(true),
             "false" | "0" | "n" => visitor.visit_bool(false),
             _ => Err(crate::Error::ExpectedBool),
         }
     }
 
+    /// Only the presence of the attribute matters for a unit, whatever its value
+    fn deserialize_unit<V: de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: de::Visitor<'de>>(self, _name: &'static str, visitor: V) -> crate::Result<V::Value> {
+        visitor.visit_unit()
+    }
+
     serde::forward_to_deserialize_any! {
-        char str string unit seq bytes map unit_struct newtype_struct tuple_struct
+        char str string seq bytes map newtype_struct tuple_struct
         struct identifier tuple ignored_any byte_buf
     }
 }
@@ -954,4 +1611,1295 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn deserialize_inner_xml_keeps_ancestor_namespaces() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            #[serde(rename = "{urn:foo}foo:baz")]
+            baz: String,
+        }
+
+        let foo = crate::from_str::<Foo>(
+            r#"<foo:bar xmlns:foo="urn:foo" xmlns:a="urn:a"><foo:baz><a:qux a:id="1"><a:quux/></a:qux></foo:baz></foo:bar>"#
+        ).unwrap();
+        assert_eq!(
+            foo.bar.baz,
+            r#"<a:qux xmlns:a="urn:a" xmlns:foo="urn:foo" a:id="1"><a:quux/></a:qux>"#
+        );
+    }
+
+    #[test]
+    fn deserialize_split_text_into_struct() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: String,
+        }
+
+        assert_eq!(
+            crate::from_str::<Foo>(
+                r#"<foo:bar xmlns:foo="urn:foo">a &amp; b<![CDATA[ <c> ]]>d</foo:bar>"#
+            )
+                .unwrap(),
+            Foo {
+                bar: "a & b <c> d".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_from_reader() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: String,
+        }
+
+        let input = r#"<foo:bar xmlns:foo="urn:foo">baz</foo:bar>"#;
+        assert_eq!(
+            crate::from_reader::<_, Foo>(input.as_bytes()).unwrap(),
+            Foo {
+                bar: "baz".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_empty_element_into_vec() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:items")]
+            items: Items,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Items {
+            #[serde(rename = "{urn:foo}foo:item", default)]
+            item: Vec<String>,
+        }
+
+        for input in [
+            r#"<foo:items xmlns:foo="urn:foo"/>"#,
+            r#"<foo:items xmlns:foo="urn:foo"></foo:items>"#,
+        ] {
+            assert_eq!(
+                crate::from_str::<Foo>(input).unwrap(),
+                Foo {
+                    items: Items {
+                        item: vec![]
+                    }
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn deserialize_element_with_comments_into_struct() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            #[serde(rename = "{urn:foo}foo:a")]
+            a: String,
+            #[serde(rename = "{urn:foo}foo:b")]
+            b: String,
+        }
+
+        let input = r#"<foo:bar xmlns:foo="urn:foo"><!-- a --><foo:a>1</foo:a><!-- b --><foo:b>2</foo:b><!-- end --></foo:bar>"#;
+        let conf = xml::ParserConfig::new()
+            .trim_whitespace(true)
+            .ignore_comments(false);
+        let events = xml::reader::EventReader::new_with_config(input.as_bytes(), conf)
+            .into_iter()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            crate::from_events::<Foo>(&events).unwrap(),
+            Foo {
+                bar: Bar {
+                    a: "1".to_string(),
+                    b: "2".to_string(),
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_struct_ignoring_text_only_element() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            #[serde(rename = "{urn:foo}foo:a")]
+            a: String,
+            #[serde(rename = "{urn:foo}foo:b")]
+            b: String,
+        }
+
+        assert_eq!(
+            crate::from_str::<Foo>(
+                r#"<foo:bar xmlns:foo="urn:foo"><foo:a>1</foo:a><foo:unknown>text</foo:unknown><foo:b>2</foo:b></foo:bar>"#
+            )
+                .unwrap(),
+            Foo {
+                bar: Bar {
+                    a: "1".to_string(),
+                    b: "2".to_string(),
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_text_into_unit_variant() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            #[serde(rename = "{urn:foo}foo:level")]
+            level: Level,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        enum Level {
+            #[serde(rename = "low")]
+            Low,
+            #[serde(rename = "high")]
+            High,
+        }
+
+        assert_eq!(
+            crate::from_str::<Foo>(
+                r#"<foo:bar xmlns:foo="urn:foo"><foo:level>high</foo:level></foo:bar>"#
+            )
+                .unwrap(),
+            Foo {
+                bar: Bar {
+                    level: Level::High,
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_element_with_namespace_declarations_into_unit_variant() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        enum Bar {
+            #[serde(rename = "{urn:bar}bar:baz")]
+            Baz,
+        }
+
+        let input = r#"<foo:bar xmlns:foo="urn:foo"><bar:baz xmlns:bar="urn:bar"/></foo:bar>"#;
+        assert_eq!(crate::from_str::<Foo>(input).unwrap(), Foo { bar: Bar::Baz });
+
+        // Declarations passed through as plain attributes should be ignored too
+        let events = xml::reader::EventReader::new(input.as_bytes()).into_iter().map(|e| match e {
+            Ok(xml::reader::XmlEvent::StartElement { name, mut attributes, namespace }) => {
+                if name.local_name == "baz" {
+                    attributes.push(xml::attribute::OwnedAttribute::new(
+                        xml::name::OwnedName::qualified("bar", xml::namespace::NS_XMLNS_URI, Some("xmlns")),
+                        "urn:bar",
+                    ));
+                }
+                Ok(xml::reader::XmlEvent::StartElement { name, attributes, namespace })
+            }
+            e => e,
+        }).collect::<Vec<_>>();
+        assert_eq!(crate::from_events::<Foo>(&events).unwrap(), Foo { bar: Bar::Baz });
+    }
+
+    #[test]
+    fn deserialize_struct_variant_with_attrs() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        enum Bar {
+            #[serde(rename = "{urn:foo}foo:baz")]
+            Baz {
+                #[serde(rename = "$attr:id")]
+                id: String,
+                #[serde(rename = "{urn:foo}foo:qux")]
+                qux: String,
+            },
+        }
+
+        assert_eq!(
+            crate::from_str::<Foo>(
+                r#"<foo:bar xmlns:foo="urn:foo"><foo:baz id="1"><foo:qux>2</foo:qux></foo:baz></foo:bar>"#
+            )
+                .unwrap(),
+            Foo {
+                bar: Bar::Baz {
+                    id: "1".to_string(),
+                    qux: "2".to_string(),
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_mixed_prefixes_into_vec() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}bar")]
+            bar: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            #[serde(rename = "{urn:foo}item")]
+            items: Vec<String>,
+        }
+
+        assert_eq!(
+            crate::from_str::<Foo>(
+                r#"<a:bar xmlns:a="urn:foo" xmlns:b="urn:foo"><a:item>1</a:item><b:item>2</b:item><a:item>3</a:item></a:bar>"#
+            )
+                .unwrap(),
+            Foo {
+                bar: Bar {
+                    items: vec!["1".to_string(), "2".to_string(), "3".to_string()],
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_attr_map() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            #[serde(rename = "$attr:id")]
+            id: String,
+            #[serde(rename = "$attr_map")]
+            extra: std::collections::HashMap<String, String>,
+        }
+
+        assert_eq!(
+            crate::from_str::<Foo>(
+                r#"<foo:bar xmlns:foo="urn:foo" id="1" data-a="2" foo:b="3"/>"#
+            )
+                .unwrap(),
+            Foo {
+                bar: Bar {
+                    id: "1".to_string(),
+                    extra: vec![
+                        ("data-a".to_string(), "2".to_string()),
+                        ("{urn:foo}foo:b".to_string(), "3".to_string()),
+                    ].into_iter().collect(),
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_mixed_content_into_struct() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:p")]
+            p: P,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct P {
+            #[serde(rename = "$value")]
+            text: String,
+            #[serde(rename = "{urn:foo}foo:b")]
+            b: String,
+        }
+
+        assert_eq!(
+            crate::from_str::<Foo>(
+                r#"<foo:p xmlns:foo="urn:foo">a &amp; b<![CDATA[c]]><foo:b>d</foo:b>e</foo:p>"#
+            )
+                .unwrap(),
+            Foo {
+                p: P {
+                    text: "a & bc".to_string(),
+                    b: "d".to_string(),
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_entity_references_into_string() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: String,
+        }
+
+        assert_eq!(
+            crate::from_str::<Foo>(
+                r#"<foo:bar xmlns:foo="urn:foo">foo&amp;bar&#x3C;baz&gt;</foo:bar>"#
+            )
+                .unwrap(),
+            Foo {
+                bar: "foo&bar<baz>".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_truncated_document() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            #[serde(rename = "{urn:foo}foo:a")]
+            a: String,
+            #[serde(rename = "{urn:foo}foo:b")]
+            b: String,
+        }
+
+        let input = r#"<foo:bar xmlns:foo="urn:foo"><foo:a>1</foo:a><foo:b>2</foo:b></foo:bar>"#;
+        assert!(matches!(crate::from_str::<Foo>(&input[..input.len() - 10]), Err(crate::Error::XMLRError(_))));
+
+        let mut events = xml::reader::EventReader::new(input.as_bytes()).into_iter().collect::<Vec<_>>();
+        events.truncate(events.len() - 2);
+        assert_eq!(
+            crate::from_events::<Foo>(&events),
+            Err(crate::Error::AtPath {
+                path: "foo:bar".to_string(),
+                error: Box::new(crate::Error::Message("unexpected end of document while reading struct fields".to_string())),
+            })
+        );
+    }
+
+    #[test]
+    fn forbid_dtd() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Doc {
+            a: String,
+        }
+
+        let input = r#"<!DOCTYPE a [<!ENTITY x "y">]><a>&x;</a>"#;
+        assert_eq!(crate::from_str::<Doc>(input).unwrap().a, "y");
+
+        let config = crate::DeserializerConfig {
+            forbid_dtd: true,
+            ..crate::DeserializerConfig::default()
+        };
+        assert_eq!(
+            crate::from_str_with_config::<Doc>(input, config),
+            Err(crate::Error::SecurityViolation("DTD not allowed"))
+        );
+    }
+
+    #[test]
+    fn forbid_external_entities() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Doc {
+            a: String,
+        }
+
+        let config = crate::DeserializerConfig {
+            forbid_external_entities: true,
+            ..crate::DeserializerConfig::default()
+        };
+        let input = r#"<!DOCTYPE a [<!ENTITY x "y">]><a>&x;</a>"#;
+        assert_eq!(crate::from_str_with_config::<Doc>(input, config.clone()).unwrap().a, "y");
+
+        let input = r#"<!DOCTYPE a [<!ENTITY x SYSTEM "file:///etc/passwd">]><a>&x;</a>"#;
+        assert_eq!(
+            crate::from_str_with_config::<Doc>(input, config.clone()),
+            Err(crate::Error::SecurityViolation("external entities not allowed"))
+        );
+        let input = r#"<!DOCTYPE a [<!ENTITY % x PUBLIC "-//x" "http://example.com/x.dtd">]><a>b</a>"#;
+        assert_eq!(
+            crate::from_str_with_config::<Doc>(input, config),
+            Err(crate::Error::SecurityViolation("external entities not allowed"))
+        );
+    }
+
+    #[test]
+    fn depth_after_value() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            a: String,
+            b: Vec<u32>,
+        }
+
+        let input = "<root><foo><a>x</a><b>1</b><b>2</b></foo></root>";
+        let mut reader = xml::reader::EventReader::from_str(input);
+        // Position the reader inside the root element
+        reader.next().unwrap();
+        reader.next().unwrap();
+        let mut deserializer = super::Deserializer::from_event_reader(reader).with_depth(1);
+        assert_eq!(deserializer.depth(), 1);
+        let foo = <std::collections::HashMap<String, Foo> as serde::Deserialize>::deserialize(&mut deserializer).unwrap();
+        assert_eq!(foo["foo"], Foo {
+            a: "x".to_string(),
+            b: vec![1, 2],
+        });
+        assert_eq!(deserializer.depth(), 1);
+    }
+
+    #[test]
+    fn deserialize_value_raw() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:a")]
+            a: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Bar {
+            #[serde(rename = "$valueRaw")]
+            raw: String,
+        }
+
+        let value = Foo {
+            a: Bar {
+                raw: r#"<foo:b x="&lt;&amp;">a &amp; b &lt; c</foo:b>"#.to_string(),
+            }
+        };
+        let xml = crate::to_string(&value).unwrap();
+        let output = crate::from_str::<Foo>(&xml).unwrap();
+        // Namespaces in scope are re-declared on the reconstructed element, but escaping is kept as it was
+        assert_eq!(output.a.raw, r#"<foo:b xmlns:foo="urn:foo" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" x="&lt;&amp;">a &amp; b &lt; c</foo:b>"#);
+        assert_eq!(crate::from_str::<Foo>(&crate::to_string(&output).unwrap()).unwrap(), output);
+    }
+
+    #[test]
+    fn deserialize_value_raw_text() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            a: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Bar {
+            #[serde(rename = "$valueRaw")]
+            raw: String,
+        }
+
+        let options = || crate::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false)
+            .build();
+        let xml = "<a>x &amp;lt; y &amp; z &lt; w</a>";
+        let output = crate::from_str::<Foo>(xml).unwrap();
+        // Text stays escaped, so it can be written back out as markup
+        assert_eq!(output.a.raw, "x &amp;lt; y &amp; z &lt; w");
+        assert_eq!(crate::to_string_custom(&output, options()).unwrap(), xml);
+        assert_eq!(crate::from_str::<Foo>(&crate::to_string_custom(&output, options()).unwrap()).unwrap(), output);
+    }
+
+    #[test]
+    fn deserialize_empty_attr_as_none() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            bar: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            #[serde(rename = "$attr:a", default)]
+            a: Option<String>,
+            #[serde(rename = "$attr:b", default)]
+            b: Option<String>,
+            #[serde(rename = "$attr:c", default)]
+            c: Option<String>,
+        }
+
+        let input = r#"<bar a="" b="x"/>"#;
+        assert_eq!(crate::from_str::<Foo>(input).unwrap().bar, Bar {
+            a: Some(String::new()),
+            b: Some("x".to_string()),
+            c: None,
+        });
+
+        let config = crate::DeserializerConfig {
+            empty_attr_is_none: true,
+            ..crate::DeserializerConfig::default()
+        };
+        assert_eq!(crate::from_str_with_config::<Foo>(input, config).unwrap().bar, Bar {
+            a: None,
+            b: Some("x".to_string()),
+            c: None,
+        });
+    }
+
+    #[test]
+    fn deserialize_mixed_element_names_into_enum_vec() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            list: List,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct List {
+            #[serde(rename = "$attr:id")]
+            id: String,
+            #[serde(rename = "$value")]
+            items: Vec<Item>,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        enum Item {
+            #[serde(rename = "foo")]
+            Foo(String),
+            #[serde(rename = "bar")]
+            Bar {
+                x: u32,
+            },
+            #[serde(rename = "baz")]
+            Baz,
+        }
+
+        let foo = crate::from_str::<Foo>(
+            r#"<list id="1"><foo>a</foo><bar><x>1</x></bar><baz/><foo>b</foo></list>"#
+        ).unwrap();
+        assert_eq!(foo.list, List {
+            id: "1".to_string(),
+            items: vec![
+                Item::Foo("a".to_string()),
+                Item::Bar { x: 1 },
+                Item::Baz,
+                Item::Foo("b".to_string()),
+            ],
+        });
+    }
+
+    #[test]
+    fn deserialize_fragment() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Stanzas {
+            message: Vec<Message>,
+            presence: Presence,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Message {
+            #[serde(rename = "$attr:to")]
+            to: String,
+            body: String,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Presence {
+            #[serde(rename = "$attr:type")]
+            presence_type: String,
+        }
+
+        // Several top level elements, which wouldn't be a well formed document
+        let stanzas = crate::from_fragment_str::<Stanzas>(
+            r#"<message to="a@example.com"><body>hi</body></message><message to="b@example.com"><body>bye</body></message><presence type="unavailable"/>"#
+        ).unwrap();
+        assert_eq!(stanzas, Stanzas {
+            message: vec![Message {
+                to: "a@example.com".to_string(),
+                body: "hi".to_string(),
+            }, Message {
+                to: "b@example.com".to_string(),
+                body: "bye".to_string(),
+            }],
+            presence: Presence {
+                presence_type: "unavailable".to_string(),
+            },
+        });
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Mixed {
+            #[serde(rename = "$value")]
+            text: String,
+            b: u32,
+        }
+
+        // Top level text
+        assert_eq!(crate::from_fragment_str::<Mixed>("text<b>1</b>").unwrap(), Mixed {
+            text: "text".to_string(),
+            b: 1,
+        });
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct A {
+            a: Vec<u32>,
+        }
+
+        // A fragment can't end the wrapping element and start another
+        assert!(crate::from_fragment_str::<A>("<a>1</a>").is_ok());
+        assert!(matches!(
+            crate::from_fragment_str::<A>("<a>1</a></fragment><fragment><a>2</a>"),
+            Err(crate::Error::XMLRError(_))
+        ));
+        assert!(crate::from_fragment_str::<A>("<a>1</a></fragment>").is_err());
+    }
+
+    #[test]
+    fn deserialize_empty_string_as_none() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            a: Option<String>,
+        }
+
+        let config = crate::DeserializerConfig {
+            empty_string_as_none: true,
+            ..crate::DeserializerConfig::default()
+        };
+        for (input, default, as_none) in [
+            ("<foo><a/></foo>", Some(String::new()), None),
+            ("<foo><a></a></foo>", Some(String::new()), None),
+            ("<foo></foo>", None, None),
+            ("<foo><a>x</a></foo>", Some("x".to_string()), Some("x".to_string())),
+        ] {
+            assert_eq!(crate::from_str::<Foo>(input).unwrap().foo.a, default);
+            assert_eq!(crate::from_str_with_config::<Foo>(input, config.clone()).unwrap().foo.a, as_none);
+        }
+    }
+
+    #[test]
+    fn match_field_ignores_prefix() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}a:foo")]
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            #[serde(rename = "{urn:foo}a:bar")]
+            bar: String,
+            #[serde(rename = "$attr:{urn:foo}a:id")]
+            id: String,
+        }
+
+        let foo = crate::from_str::<Foo>(
+            r#"<b:foo xmlns:b="urn:foo" b:id="1"><b:bar>x</b:bar></b:foo>"#
+        ).unwrap();
+        assert_eq!(foo.foo, Bar {
+            bar: "x".to_string(),
+            id: "1".to_string(),
+        });
+        // The default namespace is matched the same way
+        let foo = crate::from_str::<Foo>(
+            r#"<foo xmlns="urn:foo" xmlns:c="urn:foo" c:id="1"><bar>x</bar></foo>"#
+        ).unwrap();
+        assert_eq!(foo.foo.bar, "x");
+    }
+
+    #[test]
+    fn deserialize_list() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            sizes: Sizes,
+            #[serde(default)]
+            names: Names,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Sizes {
+            #[serde(rename = "$attr:unit")]
+            unit: String,
+            #[serde(rename = "$list")]
+            sizes: Vec<u32>,
+        }
+
+        #[derive(Debug, PartialEq, Default, Deserialize)]
+        struct Names {
+            #[serde(rename = "$list", default)]
+            names: Vec<String>,
+        }
+
+        let foo = crate::from_str::<Foo>(r#"<foo><sizes unit="cm"> 1 2
+            3  4 </sizes><names/></foo>"#).unwrap();
+        assert_eq!(foo.foo, Bar {
+            sizes: Sizes {
+                unit: "cm".to_string(),
+                sizes: vec![1, 2, 3, 4],
+            },
+            names: Names {
+                names: vec![],
+            },
+        });
+    }
+
+    #[test]
+    fn deserialize_with_whitespace_events() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            a: String,
+            b: Vec<u32>,
+        }
+
+        let mut reader = xml::reader::EventReader::new_with_config(
+            "<foo>\n  <a>x</a>\n  <b>1</b>\n  <b>2</b>\n</foo>".as_bytes(),
+            xml::ParserConfig::new().whitespace_to_characters(false),
+        );
+        assert!(matches!(reader.next(), Ok(xml::reader::XmlEvent::StartDocument { .. })));
+        let mut de = super::Deserializer::from_event_reader(reader);
+        assert_eq!(<Foo as serde::Deserialize>::deserialize(&mut de).unwrap(), Foo {
+            foo: Bar {
+                a: "x".to_string(),
+                b: vec![1, 2],
+            }
+        });
+
+        let events = xml::reader::EventReader::new_with_config(
+            "<foo>\n  <a>x</a>\n  <b>1</b>\n</foo>".as_bytes(),
+            xml::ParserConfig::new().whitespace_to_characters(false),
+        ).into_iter().collect::<Vec<_>>();
+        assert!(events.iter().any(|e| matches!(e, Ok(xml::reader::XmlEvent::Whitespace(_)))));
+        assert_eq!(crate::from_events::<Foo>(&events).unwrap(), Foo {
+            foo: Bar {
+                a: "x".to_string(),
+                b: vec![1],
+            }
+        });
+    }
+
+    #[test]
+    fn deserialize_unbalanced_end_element() {
+        let end = || Ok(xml::reader::XmlEvent::EndElement {
+            name: xml::name::OwnedName::local("foo"),
+        });
+        let events = vec![
+            end(),
+            Ok(xml::reader::XmlEvent::Characters("x".to_string())),
+            end(),
+        ];
+        assert_eq!(
+            crate::from_events::<serde::de::IgnoredAny>(&events).unwrap_err(),
+            crate::Error::ExpectedElement
+        );
+    }
+
+    #[test]
+    fn deserialize_text_unit_variants_leave_following_events() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            bar: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            level: Vec<Level>,
+            other: Level,
+            name: String,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        enum Level {
+            #[serde(rename = "low")]
+            Low,
+            #[serde(rename = "high")]
+            High,
+        }
+
+        assert_eq!(
+            crate::from_str::<Foo>(
+                "<bar><level>high</level><level>low</level><other><high/></other><name>x</name></bar>"
+            ).unwrap(),
+            Foo {
+                bar: Bar {
+                    level: vec![Level::High, Level::Low],
+                    other: Level::High,
+                    name: "x".to_string(),
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_element_stream() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        enum Stanza {
+            #[serde(rename = "{jabber:client}message")]
+            Message {
+                #[serde(rename = "$attr:to")]
+                to: String,
+                #[serde(rename = "{jabber:client}body")]
+                body: String,
+            },
+            #[serde(rename = "{jabber:client}presence")]
+            Presence {},
+        }
+
+        let input = r#"<message xmlns="jabber:client" to="a@example.com"><body>hi</body></message>
+            <presence xmlns="jabber:client"/>
+            <message xmlns="jabber:client" to="b@example.com"><body>bye</body></message>"#;
+        let stanzas = super::DeserializerIter::<Stanza, _>::new(input.as_bytes())
+            .collect::<crate::Result<Vec<_>>>().unwrap();
+        assert_eq!(stanzas, vec![
+            Stanza::Message { to: "a@example.com".to_string(), body: "hi".to_string() },
+            Stanza::Presence {},
+            Stanza::Message { to: "b@example.com".to_string(), body: "bye".to_string() },
+        ]);
+
+        let mut iter = super::DeserializerIter::<Stanza, _>::new("<presence xmlns=\"jabber:client\"/><message>".as_bytes());
+        assert_eq!(iter.next(), Some(Ok(Stanza::Presence {})));
+        assert!(matches!(iter.next(), Some(Err(_))));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn deserialize_other_variant() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            #[serde(rename = "$value")]
+            items: Vec<Item>,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        enum Item {
+            #[serde(rename = "a")]
+            A(String),
+            #[serde(rename = "b")]
+            B {},
+            #[serde(other)]
+            Other,
+        }
+
+        assert_eq!(
+            crate::from_str::<Foo>(
+                r#"<foo><a>1</a><x/><b/><y id="2"><z>3</z>text</y><a>4</a></foo>"#
+            ).unwrap(),
+            Foo {
+                foo: Bar {
+                    items: vec![
+                        Item::A("1".to_string()),
+                        Item::Other,
+                        Item::B {},
+                        Item::Other,
+                        Item::A("4".to_string()),
+                    ],
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn parse_error_position() {
+        #[derive(Debug, Deserialize)]
+        struct Foo {
+            #[serde(rename = "foo")]
+            _foo: String,
+        }
+
+        let err = crate::from_str::<Foo>("<foo>\n  <bar></baz>\n</foo>").unwrap_err();
+        let position = err.position().unwrap();
+        assert_eq!((position.row, position.column), (1, 12));
+        assert!(err.to_string().ends_with(" at line 2, column 13"), "{}", err);
+        assert_eq!(crate::Error::ExpectedElement.position(), None);
+    }
+
+    #[test]
+    fn missing_field_path() {
+        #[derive(Debug, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:foo")]
+            _foo: Bar,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct Bar {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            _bar: Vec<Baz>,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct Baz {
+            #[serde(rename = "{urn:foo}foo:a")]
+            _a: String,
+            #[serde(rename = "{urn:foo}foo:b")]
+            _b: String,
+        }
+
+        let err = crate::from_str::<Foo>(
+            r#"<f:foo xmlns:f="urn:foo"><f:bar><f:a>1</f:a><f:b>2</f:b></f:bar><f:bar><f:a>3</f:a></f:bar></f:foo>"#
+        ).unwrap_err();
+        assert_eq!(err, crate::Error::AtPath {
+            path: "f:foo/f:bar".to_string(),
+            error: Box::new(crate::Error::Message("missing field `{urn:foo}foo:b`".to_string())),
+        });
+        assert_eq!(err.to_string(), "missing field `{urn:foo}foo:b` in f:foo/f:bar");
+    }
+
+    #[test]
+    fn nested_read_inner_value() {
+        let events = xml::reader::EventReader::new("<a>x<b>y</b>z</a>".as_bytes()).into_iter().skip(1);
+        let mut de = super::Deserializer::new(super::new_reader(events));
+        de.set_map_value();
+        // The closure gets the deserializer back, so can read further events and elements with it
+        let text = de.read_inner_value(|this| {
+            let mut text = this.read_text_coalesced()?;
+            this.set_map_value();
+            text.push_str(&this.read_inner_value(|this| this.read_text_coalesced())?);
+            match this.next_significant()? {
+                xml::reader::XmlEvent::Characters(s) => text.push_str(&s),
+                _ => return Err(crate::Error::ExpectedString)
+            }
+            Ok(text)
+        }).unwrap();
+        assert_eq!(text, "xyz");
+        assert_eq!(de.depth(), 0);
+    }
+
+    #[test]
+    fn map_value_before_key() {
+        struct ValueFirst;
+
+        impl<'de> serde::Deserialize<'de> for ValueFirst {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                deserializer.deserialize_map(ValueFirst)
+            }
+        }
+
+        impl<'de> serde::de::Visitor<'de> for ValueFirst {
+            type Value = ValueFirst;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a map")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                map.next_value::<String>()?;
+                Ok(ValueFirst)
+            }
+        }
+
+        assert_eq!(
+            crate::from_str::<ValueFirst>("<foo><bar>1</bar></foo>").err(),
+            Some(crate::Error::ExpectedElement)
+        );
+    }
+
+    #[test]
+    fn deserialize_schema_instance_attrs() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        enum Bar {
+            #[serde(rename = "{urn:foo}foo:a")]
+            A {
+                #[serde(rename = "$attr:id")]
+                id: String,
+            },
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Baz {
+            #[serde(rename = "$attr:id")]
+            id: String,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Typed {
+            #[serde(rename = "$attr:{http://www.w3.org/2001/XMLSchema-instance}type")]
+            xsi_type: String,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Doc<T> {
+            doc: T,
+        }
+
+        assert_eq!(
+            crate::from_str::<Foo>(r#"<foo:bar xmlns:foo="urn:foo"><foo:a xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:type="foo:A" id="1"/></foo:bar>"#).unwrap(),
+            Foo { bar: Bar::A { id: "1".to_string() } }
+        );
+        assert_eq!(
+            crate::from_str::<Doc<Baz>>(r#"<doc xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="urn:foo foo.xsd" xsi:noNamespaceSchemaLocation="doc.xsd" id="1"/>"#).unwrap(),
+            Doc { doc: Baz { id: "1".to_string() } }
+        );
+        assert_eq!(
+            crate::from_str::<Doc<Typed>>(r#"<doc xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:type="a"/>"#).unwrap(),
+            Doc { doc: Typed { xsi_type: "a".to_string() } }
+        );
+    }
+
+    #[test]
+    fn max_text_length_and_attributes() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            a: String,
+        }
+
+        let config = crate::DeserializerConfig {
+            max_text_length: Some(4),
+            max_attributes: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            crate::from_str_with_config::<Foo>("<foo><a>abcd</a></foo>", config.clone()).unwrap(),
+            Foo { foo: Bar { a: "abcd".to_string() } }
+        );
+        assert_eq!(
+            crate::from_str_with_config::<Foo>("<foo><a>abc<![CDATA[de]]></a></foo>", config.clone()),
+            Err(crate::Error::SecurityViolation("text content exceeds max_text_length"))
+        );
+        assert_eq!(
+            crate::from_str_with_config::<Foo>(
+                r#"<!DOCTYPE foo [<!ENTITY x "abcde">]><foo><a>&x;</a></foo>"#, config.clone(),
+            ),
+            Err(crate::Error::SecurityViolation("text content exceeds max_text_length"))
+        );
+        assert_eq!(
+            crate::from_str_with_config::<Foo>(r#"<foo x="1" y="2"><a>a</a></foo>"#, config),
+            Err(crate::Error::SecurityViolation("attribute count exceeds max_attributes"))
+        );
+    }
+
+    #[test]
+    fn deserialize_with_comments() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            a: String,
+            b: Vec<u32>,
+            #[serde(rename = "$value")]
+            value: String,
+        }
+
+        let xml = "<!-- a --><foo>x<!-- b -->y<a>1<!-- c -->2</a><!-- d --><b>1</b><!-- e --><b>2</b></foo><!-- f -->";
+        let config = crate::DeserializerConfig {
+            ignore_comments: false,
+            ..Default::default()
+        };
+        let expected = Foo {
+            foo: Bar {
+                a: "12".to_string(),
+                b: vec![1, 2],
+                value: "xy".to_string(),
+            }
+        };
+        assert_eq!(crate::from_str_with_config::<Foo>(xml, config).unwrap(), expected);
+        assert_eq!(crate::from_str::<Foo>(xml).unwrap(), expected);
+    }
+
+    #[test]
+    fn debug_deserializer() {
+        let events = vec![
+            Ok(xml::reader::XmlEvent::StartElement {
+                name: xml::name::OwnedName::local("a"),
+                attributes: vec![],
+                namespace: xml::namespace::Namespace::empty(),
+            }),
+        ];
+        let mut de = super::Deserializer::new(super::new_reader(events));
+        de.next().unwrap();
+        let debug = format!("{:?}", de);
+        assert!(debug.starts_with("Deserializer { depth: 1, is_map_value: false,"), "{}", debug);
+        assert!(debug.ends_with(", .. }"), "{}", debug);
+    }
+
+    #[test]
+    fn parser_options() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            a: String,
+        }
+
+        let parse = |xml, config| crate::from_str_with_config::<Foo>(xml, config);
+        assert_eq!(
+            parse("<foo><a> 1 </a></foo>", crate::DeserializerConfig::default()).unwrap(),
+            Foo { foo: Bar { a: "1".to_string() } }
+        );
+        assert_eq!(
+            parse("<foo><a> 1 </a></foo>", crate::DeserializerConfig {
+                trim_whitespace: false,
+                ..Default::default()
+            }).unwrap(),
+            Foo { foo: Bar { a: " 1 ".to_string() } }
+        );
+    }
+
+    #[test]
+    fn max_depth() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            a: String,
+        }
+
+        let config = |max_depth| crate::DeserializerConfig {
+            max_depth: Some(max_depth),
+            ..Default::default()
+        };
+        assert!(crate::from_str_with_config::<Foo>("<foo><a>1</a></foo>", config(2)).is_ok());
+        assert_eq!(
+            crate::from_str_with_config::<Foo>("<foo><a>1</a></foo>", config(1)),
+            Err(crate::Error::SecurityViolation("element nesting exceeds max_depth"))
+        );
+    }
+
+    #[test]
+    fn deserialize_unit_attrs() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Marker;
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            #[serde(rename = "$attr:a")]
+            a: (),
+            #[serde(rename = "$attr:b")]
+            b: Option<()>,
+            #[serde(rename = "$attr:c")]
+            c: Option<Marker>,
+        }
+
+        assert_eq!(
+            crate::from_str::<Foo>(r#"<foo a="" b="yes" c=""/>"#).unwrap(),
+            Foo { foo: Bar { a: (), b: Some(()), c: Some(Marker) } }
+        );
+        assert_eq!(
+            crate::from_str::<Foo>(r#"<foo a="x"/>"#).unwrap(),
+            Foo { foo: Bar { a: (), b: None, c: None } }
+        );
+    }
+
+    #[test]
+    fn skip_element() {
+        let reader = xml::reader::EventReader::from_str("<a><b><c>1</c></b><d>2</d></a>");
+        let mut de = super::Deserializer::from_event_reader(reader);
+        assert!(matches!(de.next_significant().unwrap(), xml::reader::XmlEvent::StartDocument { .. }));
+        assert!(matches!(de.next_significant().unwrap(), xml::reader::XmlEvent::StartElement { .. }));
+        de.skip_element().unwrap();
+        assert_eq!(de.depth(), 1);
+        match de.next_significant().unwrap() {
+            xml::reader::XmlEvent::StartElement { name, .. } => assert_eq!(name.local_name, "d"),
+            e => panic!("unexpected event {:?}", e)
+        }
+        assert_eq!(de.skip_element(), Err(crate::Error::ExpectedElement));
+        // Nothing is consumed when there's no element to skip
+        assert_eq!(de.depth(), 2);
+        match de.next_significant().unwrap() {
+            xml::reader::XmlEvent::Characters(s) => assert_eq!(s, "2"),
+            e => panic!("unexpected event {:?}", e)
+        }
+    }
+
+    #[test]
+    fn deserialize_leading_whitespace() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            foo: String,
+        }
+
+        for input in ["\n  <?xml version=\"1.0\"?><foo>bar</foo>", "\n<foo>bar</foo>"] {
+            assert_eq!(crate::from_str::<Foo>(input).unwrap(), Foo { foo: "bar".to_string() });
+        }
+    }
+
+    #[test]
+    fn deserialize_unfiltered_whitespace() {
+        use serde::Deserialize;
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Doc {
+            foo: Foo,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            a: u32,
+            bar: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            b: String,
+        }
+
+        // Whitespace between elements comes through as Whitespace events rather than being dropped
+        let input = "<foo>\n  <a>1</a>\n  <bar>\n    <b>x</b>\n  </bar>\n</foo>";
+        let conf = xml::ParserConfig::new()
+            .trim_whitespace(false)
+            .whitespace_to_characters(false);
+        let events = xml::reader::EventReader::new_with_config(input.as_bytes(), conf).into_iter().skip(1);
+        let mut de = super::Deserializer::new(itertools::multipeek(events));
+        assert_eq!(Doc::deserialize(&mut de).unwrap().foo, Foo {
+            a: 1,
+            bar: Bar {
+                b: "x".to_string(),
+            },
+        });
+    }
 }
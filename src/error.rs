@@ -2,13 +2,78 @@
 pub enum Error {
     XMLWError(xml::writer::Error),
     XMLRError(xml::reader::Error),
+    IO(std::io::Error),
     Message(String),
     ExpectedString,
     ExpectedChar,
     ExpectedBool,
     ExpectedInt,
+    ExpectedFloat,
     ExpectedElement,
-    Unsupported
+    Unsupported,
+    /// The document was rejected by a restriction in `DeserializerConfig`
+    SecurityViolation(&'static str),
+    /// A tag name that isn't a valid XML name was given for an element or attribute
+    InvalidElementName(String),
+    /// An error from within an element, such as a missing field, with the path of element names
+    /// leading to it
+    AtPath { path: String, error: Box<Error> },
+}
+
+/// Errors are compared structurally where possible. The wrapped xml-rs errors are compared by
+/// their string representation, and I/O errors by their kind and string representation.
+///
+/// ```
+/// let result = xml_serde::from_str::<bool>("<a>maybe</a>");
+/// assert_eq!(result, Err(xml_serde::Error::ExpectedBool));
+/// ```
+impl PartialEq for Error {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Error::XMLWError(a), Error::XMLWError(b)) => a.to_string() == b.to_string(),
+            (Error::XMLRError(a), Error::XMLRError(b)) => a.to_string() == b.to_string(),
+            (Error::IO(a), Error::IO(b)) => a.kind() == b.kind() && a.to_string() == b.to_string(),
+            (Error::Message(a), Error::Message(b)) => a == b,
+            (Error::SecurityViolation(a), Error::SecurityViolation(b)) => a == b,
+            (Error::InvalidElementName(a), Error::InvalidElementName(b)) => a == b,
+            (Error::AtPath { path: a, error: e_a }, Error::AtPath { path: b, error: e_b }) => a == b && e_a == e_b,
+            (Error::ExpectedString, Error::ExpectedString) |
+            (Error::ExpectedChar, Error::ExpectedChar) |
+            (Error::ExpectedBool, Error::ExpectedBool) |
+            (Error::ExpectedInt, Error::ExpectedInt) |
+            (Error::ExpectedFloat, Error::ExpectedFloat) |
+            (Error::ExpectedElement, Error::ExpectedElement) |
+            (Error::Unsupported, Error::Unsupported) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Error {
+    /// Where in the document parsing failed, for errors from the XML parser
+    pub fn position(&self) -> Option<xml::common::TextPosition> {
+        match self {
+            Error::XMLRError(err) => Some(xml::common::Position::position(err)),
+            Error::AtPath { error, .. } => error.position(),
+            _ => None,
+        }
+    }
+
+    /// Add the element an error happened within to its path. Only errors from serde, such as
+    /// missing fields, are given a path, as the others are specific enough already.
+    pub(crate) fn in_element(self, name: &xml::name::OwnedName) -> Self {
+        match self {
+            Error::Message(_) => Error::AtPath {
+                path: name.borrow().to_repr(),
+                error: Box::new(self),
+            },
+            Error::AtPath { path, error } => Error::AtPath {
+                path: format!("{}/{}", name.borrow().to_repr(), path),
+                error,
+            },
+            e => e
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -19,6 +84,54 @@ impl serde::ser::Error for Error {
     }
 }
 
+/// Hand-written `Deserialize` implementations can be mixed with derived ones; errors from them
+/// are made with `serde::de::Error::custom`, and come out as `Error::Message` (within an
+/// `Error::AtPath` giving the elements they were found in).
+///
+/// ```
+/// use serde_derive::Deserialize;
+///
+/// /// A point written as text, such as `<point>1,2</point>`
+/// #[derive(Debug, PartialEq)]
+/// struct Point {
+///     x: i32,
+///     y: i32,
+/// }
+///
+/// impl<'de> serde::Deserialize<'de> for Point {
+///     fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+///         use serde::de::Error;
+///
+///         let s = String::deserialize(deserializer)?;
+///         let (x, y) = s.split_once(',').ok_or_else(|| D::Error::custom("expected x,y"))?;
+///         Ok(Point {
+///             x: x.trim().parse().map_err(D::Error::custom)?,
+///             y: y.trim().parse().map_err(D::Error::custom)?,
+///         })
+///     }
+/// }
+///
+/// #[derive(Debug, PartialEq, Deserialize)]
+/// struct Shape {
+///     name: String,
+///     point: Point,
+/// }
+///
+/// #[derive(Debug, PartialEq, Deserialize)]
+/// struct Doc {
+///     shape: Shape,
+/// }
+///
+/// let doc = xml_serde::from_str::<Doc>("<shape><name>a</name><point>1,2</point></shape>").unwrap();
+/// assert_eq!(doc.shape.point, Point { x: 1, y: 2 });
+/// assert_eq!(
+///     xml_serde::from_str::<Doc>("<shape><name>a</name><point>1</point></shape>"),
+///     Err(xml_serde::Error::AtPath {
+///         path: "shape".to_string(),
+///         error: Box::new(xml_serde::Error::Message("expected x,y".to_string())),
+///     })
+/// );
+/// ```
 impl serde::de::Error for Error {
     fn custom<T: std::fmt::Display>(msg: T) -> Self {
         Error::Message(msg.to_string())
@@ -30,18 +143,36 @@ impl std::fmt::Display for Error {
         match self {
             Error::Message(msg) => formatter.write_str(msg),
             Error::XMLWError(err) => formatter.write_str(&err.to_string()),
-            Error::XMLRError(err) => formatter.write_str(&err.to_string()),
+            Error::XMLRError(err) => {
+                let position = xml::common::Position::position(err);
+                write!(formatter, "{} at line {}, column {}", err.msg(), position.row + 1, position.column + 1)
+            }
+            Error::IO(err) => formatter.write_str(&err.to_string()),
             Error::ExpectedString => formatter.write_str("expected a string"),
             Error::ExpectedChar => formatter.write_str("expected a char"),
             Error::ExpectedBool => formatter.write_str("expected a bool"),
             Error::ExpectedInt => formatter.write_str("expected a number"),
+            Error::ExpectedFloat => formatter.write_str("expected a floating point number"),
             Error::ExpectedElement => formatter.write_str("expected an element"),
             Error::Unsupported => formatter.write_str("unsupported operation"),
+            Error::SecurityViolation(msg) => formatter.write_str(msg),
+            Error::InvalidElementName(name) => write!(formatter, "invalid XML element name: {:?}", name),
+            Error::AtPath { path, error } => write!(formatter, "{} in {}", error, path),
         }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::XMLWError(err) => Some(err),
+            Error::XMLRError(err) => Some(err),
+            Error::IO(err) => Some(err),
+            Error::AtPath { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
 
 impl From<xml::writer::Error> for Error {
     fn from(err: xml::writer::Error) -> Self {
@@ -49,6 +180,12 @@ impl From<xml::writer::Error> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::IO(err)
+    }
+}
+
 impl From<xml::reader::Error> for Error {
     fn from(err: xml::reader::Error) -> Self {
         Error::XMLRError(err)
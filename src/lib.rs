@@ -5,19 +5,57 @@ extern crate serde_derive;
 extern crate log;
 extern crate core;
 
+/// Assert that two XML documents are the same, ignoring whitespace between elements
+#[cfg(test)]
+macro_rules! assert_xml_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => assert!(
+                crate::tests::xml_eq(left, right),
+                "assertion `left == right` failed (ignoring whitespace)\n  left: {}\n right: {}", left, right,
+            )
+        }
+    };
+}
+
 mod de;
 mod ser;
 mod error;
 mod tag;
+mod value;
 
-pub(crate) use tag::Tag;
+pub use tag::Tag;
 
-pub use ser::{to_string, to_string_custom, to_events, to_events_custom, Serializer, Options};
-pub use de::{from_str, from_string, from_events, Deserializer};
+pub use ser::{
+    to_string, to_string_custom, to_fragment_string, to_writer, to_writer_custom, to_events, to_events_custom,
+    format_data, Serializer, SerializerData, SerializerState, EventWriter, Options, OptionsBuilder, LineEnding,
+};
+pub use de::{
+    from_str, from_str_with_config, from_string, from_string_with_config, from_reader, from_reader_with_config,
+    from_fragment_str, from_events, Deserializer, DeserializerIter, DeserializerConfig,
+};
 pub use error::{Error, Result};
+pub use value::XmlValue;
 
 #[cfg(test)]
 mod tests {
+    /// Whether two XML documents parse to the same events, other than whitespace between elements
+    pub(crate) fn xml_eq(a: &str, b: &str) -> bool {
+        fn events(s: &str) -> Vec<xml::reader::XmlEvent> {
+            xml::reader::EventReader::from_str(s).into_iter()
+                .map(|e| e.unwrap())
+                .filter(|e| !matches!(e, xml::reader::XmlEvent::Whitespace(_)))
+                .collect()
+        }
+        events(a) == events(b)
+    }
+
+    #[test]
+    fn xml_eq_ignores_whitespace() {
+        assert_xml_eq!("<a>\n  <b>1</b>\n</a>", "<a><b>1</b></a>");
+        assert!(!xml_eq("<a><b>1</b></a>", "<a><b> 1</b></a>"));
+        assert!(!xml_eq(r#"<a x="1"/>"#, r#"<a x="2"/>"#));
+    }
 
     #[derive(Debug, Serialize, Deserialize)]
     pub enum EPPMessageType {
@@ -89,4 +127,420 @@ mod tests {
             })
         }).unwrap());
     }
+
+    #[test]
+    fn encode_raw_command() {
+        let options = || super::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false)
+            .indent_size(0)
+            .build();
+        let login = super::to_string_custom(&EPPCommandType::Login(EPPLogin {
+            client_id: "client".to_string(),
+            password: "a & b".to_string(),
+            new_password: None,
+            options: EPPLoginOptions {
+                version: "1.0".to_string(),
+                language: "en".to_string(),
+            },
+            services: EPPLoginServices {
+                objects: vec!["urn:ietf:params:xml:ns:domain-1.0".to_string()],
+            },
+        }), options()).unwrap();
+        let xml = super::to_string_custom(&EPPMessage {
+            message: EPPMessageType::Command(EPPCommand {
+                command: login.clone(),
+                client_transaction_id: None,
+            })
+        }, options()).unwrap();
+        // The command is already XML, so it's written as is
+        assert!(xml.contains(&login), "{}", xml);
+        assert!(xml.contains("<pw>a &amp; b</pw>"), "{}", xml);
+    }
+
+    #[test]
+    fn encode_without_schema_location() {
+        let message = EPPMessage {
+            message: EPPMessageType::Command(EPPCommand {
+                command: "&".to_string(),
+                client_transaction_id: Some("&".to_string()),
+            })
+        };
+        let options = super::Options::builder()
+            .include_schema_location(false)
+            .build();
+        let xml = super::to_string_custom(&message, options).unwrap();
+        assert!(!xml.contains("xsi"), "{}", xml);
+        assert!(super::to_string(&message).unwrap().contains(r#" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance""#));
+    }
+
+    #[test]
+    fn to_events_transparent() {
+        #[derive(Debug, Serialize)]
+        #[serde(transparent)]
+        struct Transparent {
+            message: EPPMessageType,
+        }
+
+        let command = || EPPMessageType::Command(EPPCommand {
+            command: String::new(),
+            client_transaction_id: Some("a".to_string()),
+        });
+        let events = super::to_events(&Transparent { message: command() }).unwrap();
+        assert_eq!(events, super::to_events(&command()).unwrap());
+        let root = events.iter().find_map(|e| match e {
+            xml::reader::XmlEvent::StartElement { name, .. } => Some(name.clone()),
+            _ => None
+        });
+        assert_eq!(root, Some(xml::name::OwnedName::qualified(
+            "command", "urn:ietf:params:xml:ns:epp-1.0", None::<String>,
+        )));
+    }
+
+    #[test]
+    fn serialize_skipped_variants() {
+        let xml = super::to_string(&EPPMessage {
+            message: EPPMessageType::Hello {},
+        }).unwrap();
+        assert_xml_eq!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>
+<epp xmlns="urn:ietf:params:xml:ns:epp-1.0" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="urn:ietf:params:xml:ns:epp-1.0 epp-1.0.xsd">
+  <hello/>
+</epp>"#);
+
+        #[derive(Debug, Serialize)]
+        enum Message {
+            #[serde(rename = "{urn:ietf:params:xml:ns:epp-1.0}hello")]
+            Hello {},
+            #[serde(rename = "{urn:ietf:params:xml:ns:epp-1.0}greeting", skip_serializing)]
+            Greeting {},
+        }
+
+        assert!(super::to_string(&Message::Hello {}).is_ok());
+        assert!(matches!(super::to_string(&Message::Greeting {}), Err(super::Error::Message(_))));
+    }
+
+    #[test]
+    fn round_trip_hash_maps() {
+        use std::collections::HashMap;
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo<T> {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: HashMap<String, T>,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Baz {
+            #[serde(rename = "{urn:foo}foo:qux")]
+            qux: String,
+        }
+
+        fn round_trip<T: serde::Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug>(value: Foo<T>) {
+            let xml = super::to_string(&value).unwrap();
+            assert_eq!(super::from_str::<Foo<T>>(&xml).unwrap(), value);
+        }
+
+        round_trip(Foo {
+            bar: vec![
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "2".to_string()),
+            ].into_iter().collect()
+        });
+        round_trip(Foo {
+            bar: vec![
+                ("a".to_string(), Baz { qux: "1".to_string() }),
+                ("b".to_string(), Baz { qux: "2".to_string() }),
+            ].into_iter().collect()
+        });
+        round_trip(Foo {
+            bar: vec![
+                ("a".to_string(), vec!["1".to_string(), "2".to_string()]),
+                ("b".to_string(), vec!["3".to_string()]),
+            ].into_iter().collect()
+        });
+    }
+
+    #[test]
+    fn round_trip_flatten() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Bar {
+            #[serde(rename = "{urn:foo}a")]
+            a: String,
+            #[serde(flatten)]
+            inner: Baz,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Baz {
+            #[serde(rename = "$attr:id")]
+            id: String,
+            #[serde(rename = "{urn:foo}b")]
+            b: String,
+        }
+
+        let value = Foo {
+            bar: Bar {
+                a: "1".to_string(),
+                inner: Baz {
+                    id: "2".to_string(),
+                    b: "3".to_string(),
+                },
+            }
+        };
+        let xml = super::to_string(&value).unwrap();
+        assert!(xml.contains(r#" id="2""#));
+        assert_eq!(super::from_str::<Foo>(&xml).unwrap(), value);
+    }
+
+    #[test]
+    fn deserialize_flatten_with_any_prefix() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:foo")]
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            #[serde(flatten)]
+            inner: Baz,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Baz {
+            #[serde(rename = "{urn:foo}a")]
+            a: String,
+        }
+
+        for input in &[
+            r#"<g:foo xmlns:g="urn:foo"><g:a>1</g:a></g:foo>"#,
+            r#"<foo xmlns="urn:foo"><a>1</a></foo>"#,
+        ] {
+            assert_eq!(super::from_str::<Foo>(input).unwrap(), Foo {
+                foo: Bar {
+                    inner: Baz { a: "1".to_string() }
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn deserialize_untagged_enum() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            #[serde(rename = "{urn:foo}foo:baz")]
+            baz: Vec<Baz>,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        #[serde(untagged)]
+        enum Baz {
+            Element {
+                #[serde(rename = "{urn:foo}a")]
+                a: String,
+            },
+            Attr {
+                #[serde(rename = "$attr:b")]
+                b: String,
+            },
+            Text(String),
+        }
+
+        assert_eq!(
+            super::from_str::<Foo>(
+                r#"<foo:bar xmlns:foo="urn:foo"><foo:baz><foo:a>1</foo:a></foo:baz><foo:baz b="2"/><foo:baz>3</foo:baz></foo:bar>"#
+            ).unwrap(),
+            Foo {
+                bar: Bar {
+                    baz: vec![
+                        Baz::Element { a: "1".to_string() },
+                        Baz::Attr { b: "2".to_string() },
+                        Baz::Text("3".to_string()),
+                    ]
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_serde_value() {
+        use serde_value::Value;
+
+        let value = super::from_str::<Value>(
+            r#"<foo:bar xmlns:foo="urn:foo" id="1"><foo:a>2</foo:a><foo:b><foo:c>3</foo:c></foo:b></foo:bar>"#
+        ).unwrap();
+        let map = |entries: Vec<(&str, Value)>| Value::Map(
+            entries.into_iter().map(|(k, v)| (Value::String(k.to_string()), v)).collect()
+        );
+        assert_eq!(value, map(vec![
+            ("{urn:foo}bar", map(vec![
+                ("$attr:id", Value::String("1".to_string())),
+                ("{urn:foo}a", Value::String("2".to_string())),
+                ("{urn:foo}b", map(vec![
+                    ("{urn:foo}c", Value::String("3".to_string())),
+                ])),
+            ])),
+        ]));
+    }
+
+    #[test]
+    fn round_trip_xml_id() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Vec<Bar>,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Bar {
+            #[serde(rename = "$attr:{http://www.w3.org/XML/1998/namespace}id")]
+            id: String,
+            #[serde(rename = "$value")]
+            value: String,
+        }
+
+        let value = Foo {
+            bar: vec![
+                Bar { id: "a".to_string(), value: "1".to_string() },
+                Bar { id: "b".to_string(), value: "2".to_string() },
+            ]
+        };
+        let xml = super::to_string(&value).unwrap();
+        assert!(xml.contains(r#" xml:id="b">2</foo:bar>"#), "{}", xml);
+        assert_eq!(super::from_str::<Foo>(&xml).unwrap(), value);
+        assert_eq!(super::from_str::<Foo>(
+            r#"<foo:bar xmlns:foo="urn:foo" xml:id="a">1</foo:bar><foo:bar xml:id="b" xmlns:foo="urn:foo">2</foo:bar>"#
+        ).unwrap(), value);
+    }
+
+    #[test]
+    fn round_trip_namespaced_struct_variant() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:foo")]
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        enum Bar {
+            #[serde(rename = "{urn:bar}bar:a")]
+            A {
+                #[serde(rename = "$attr:id")]
+                id: String,
+                #[serde(rename = "{urn:bar}bar:b")]
+                b: String,
+            },
+        }
+
+        let value = Foo {
+            foo: Bar::A { id: "1".to_string(), b: "2".to_string() },
+        };
+        let xml = super::to_string(&value).unwrap();
+        assert!(xml.contains(r#"<bar:a xmlns:bar="urn:bar" id="1">"#), "{}", xml);
+        assert!(xml.contains(r#"xsi:schemaLocation="urn:foo foo.xsd urn:bar bar.xsd""#), "{}", xml);
+        assert_eq!(super::from_str::<Foo>(&xml).unwrap(), value);
+    }
+
+    mod round_trip_props {
+        use proptest::prelude::*;
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        struct Flat {
+            a: String,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            b: Option<String>,
+            c: u32,
+            #[serde(default)]
+            d: Vec<String>,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        struct FlatDoc {
+            flat: Flat,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        struct Namespaced {
+            #[serde(rename = "{urn:test}t:a")]
+            a: String,
+            #[serde(rename = "{urn:other}o:b", default)]
+            b: Vec<u32>,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        struct NamespacedDoc {
+            #[serde(rename = "{urn:test}t:doc")]
+            doc: Namespaced,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        struct Attrs {
+            #[serde(rename = "$attr:id")]
+            id: String,
+            #[serde(rename = "$attr:count")]
+            count: u32,
+            #[serde(rename = "$value")]
+            value: String,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        struct AttrsDoc {
+            attrs: Attrs,
+        }
+
+        fn text() -> impl Strategy<Value=String> {
+            "[a-zA-Z0-9&<>'\"]([a-zA-Z0-9 &<>'\"]*[a-zA-Z0-9&<>'\"])?"
+        }
+
+        proptest! {
+            #![proptest_config(ProptestConfig::with_cases(1000))]
+
+            #[test]
+            fn round_trip_flat(
+                a in text(), b in proptest::option::of(text()), c in any::<u32>(),
+                d in proptest::collection::vec(text(), 0..4),
+            ) {
+                let v = FlatDoc { flat: Flat { a, b, c, d } };
+                let xml = crate::to_string(&v).unwrap();
+                prop_assert_eq!(crate::from_str::<FlatDoc>(&xml), Ok(v));
+            }
+
+            #[test]
+            fn round_trip_namespaced(a in text(), b in proptest::collection::vec(any::<u32>(), 0..4)) {
+                let v = NamespacedDoc { doc: Namespaced { a, b } };
+                let xml = crate::to_string(&v).unwrap();
+                prop_assert_eq!(crate::from_str::<NamespacedDoc>(&xml), Ok(v));
+            }
+
+            #[test]
+            fn round_trip_attrs(id in text(), count in any::<u32>(), value in text()) {
+                let v = AttrsDoc { attrs: Attrs { id, count, value } };
+                let xml = crate::to_string(&v).unwrap();
+                prop_assert_eq!(crate::from_str::<AttrsDoc>(&xml), Ok(v));
+            }
+
+            #[test]
+            fn to_writer_matches_to_string(
+                a in text(), b in proptest::collection::vec(any::<u32>(), 0..4), hoist in any::<bool>(),
+            ) {
+                let v = NamespacedDoc { doc: Namespaced { a, b } };
+                let options = crate::Options::builder().hoist_namespaces(hoist);
+                let mut out = vec![];
+                crate::to_writer_custom(&mut out, &v, options.clone().build()).unwrap();
+                prop_assert_eq!(String::from_utf8(out).unwrap(), crate::to_string_custom(&v, options.build()).unwrap());
+            }
+        }
+    }
 }
@@ -2,6 +2,8 @@
 //!
 //! The special serde tag name `$value` equates to the inner value of an XML element.
 //! Tags starting with `$attr:` will be encoded as attributes rather than new elements.
+//! The special serde tag name `$attr_map` spreads the entries of a map as attributes.
+//! The special serde tag name `$list` writes a sequence as whitespace separated text, as an `xs:list`.
 //! Namespaces and prefixes can be set using the tag name format `{namespace}prefix:tag-name`.
 
 use std::borrow::Cow;
@@ -10,24 +12,83 @@ use crate::Tag;
 
 pub struct Serializer;
 
-trait EventWriter {
-    fn write<'a, E: Into<xml::writer::XmlEvent<'a>>>(&mut self, event: E) -> xml::writer::Result<()>;
+/// A destination for the XML events produced by `format_data`
+pub trait EventWriter {
+    fn write(&mut self, event: xml::writer::XmlEvent<'_>) -> xml::writer::Result<()>;
 }
 
-struct EmitterWriter<W: std::io::Write>(xml::writer::EventWriter<W>);
-
-impl<W: std::io::Write> EventWriter for EmitterWriter<W> {
-    fn write<'a, E: Into<xml::writer::XmlEvent<'a>>>(&mut self, event: E) -> xml::writer::Result<()> {
-        self.0.write(event)
+impl<W: std::io::Write> EventWriter for xml::writer::EventWriter<W> {
+    fn write(&mut self, event: xml::writer::XmlEvent<'_>) -> xml::writer::Result<()> {
+        xml::writer::EventWriter::write(self, event)
     }
 }
 
-struct ListWriter(Vec<xml::reader::XmlEvent>);
+#[derive(Default)]
+struct ListWriter {
+    events: Vec<xml::reader::XmlEvent>,
+    /// Names of the currently open elements, as the writer's end element events may not carry them
+    open: Vec<xml::name::OwnedName>,
+    /// Namespaces in scope in each of the currently open elements, to resolve prefixes with as
+    /// the parser would
+    scopes: Vec<xml::namespace::Namespace>,
+}
+
+impl ListWriter {
+    /// Undo the escaping done by `format_data`, as reader events hold text as it would be parsed
+    fn unescape(s: &str) -> Cow<'_, str> {
+        if !s.contains('&') {
+            return Cow::Borrowed(s);
+        }
+        let mut out = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(i) = rest.find('&') {
+            out.push_str(&rest[..i]);
+            rest = &rest[i..];
+            let reference = rest.find(';').and_then(|end| {
+                let c = match &rest[1..end] {
+                    "lt" => Some('<'),
+                    "gt" => Some('>'),
+                    "amp" => Some('&'),
+                    "quot" => Some('"'),
+                    "apos" => Some('\''),
+                    r => r.strip_prefix("#x").map(|h| u32::from_str_radix(h, 16))
+                        .or_else(|| r.strip_prefix('#').map(|d| d.parse::<u32>()))
+                        .and_then(Result::ok)
+                        .and_then(char::from_u32),
+                };
+                c.map(|c| (c, end))
+            });
+            match reference {
+                Some((c, end)) => {
+                    out.push(c);
+                    rest = &rest[end + 1..];
+                }
+                // Not a reference we know, so leave it be
+                None => {
+                    out.push('&');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        out.push_str(rest);
+        Cow::Owned(out)
+    }
+
+    fn resolve_name(namespace: &xml::namespace::Namespace, name: xml::name::Name<'_>, is_attr: bool) -> xml::name::OwnedName {
+        let mut name = name.to_owned();
+        // Unprefixed attributes are never in a namespace, not even the default one
+        if name.namespace.is_none() && !(is_attr && name.prefix.is_none()) {
+            name.namespace = namespace.get(name.prefix_ref().unwrap_or(xml::namespace::NS_NO_PREFIX))
+                .filter(|n| !n.is_empty())
+                .map(Into::into);
+        }
+        name
+    }
+}
 
 impl EventWriter for ListWriter {
-    fn write<'a, E: Into<xml::writer::XmlEvent<'a>>>(&mut self, event: E) -> xml::writer::Result<()> {
-        let e = event.into();
-        let re = match e {
+    fn write(&mut self, event: xml::writer::XmlEvent<'_>) -> xml::writer::Result<()> {
+        let re = match event {
             xml::writer::XmlEvent::StartDocument { version, encoding, standalone } => {
                 xml::reader::XmlEvent::StartDocument {
                     version,
@@ -42,47 +103,203 @@ impl EventWriter for ListWriter {
                 }
             }
             xml::writer::XmlEvent::StartElement { name, attributes, namespace } => {
+                let mut scope = self.scopes.last().cloned().unwrap_or_else(xml::namespace::Namespace::empty);
+                for (prefix, uri) in namespace.iter() {
+                    scope.force_put(prefix, uri);
+                }
+                let name = Self::resolve_name(&scope, name, false);
+                let attributes = attributes.iter().map(|a| xml::attribute::OwnedAttribute {
+                    name: Self::resolve_name(&scope, a.name, true),
+                    value: Self::unescape(a.value).into_owned(),
+                }).collect();
+                self.open.push(name.clone());
+                self.scopes.push(scope.clone());
                 xml::reader::XmlEvent::StartElement {
-                    name: name.to_owned(),
-                    attributes: (*attributes).iter().map(|a| a.to_owned()).collect(),
-                    namespace: (*namespace).clone(),
+                    name,
+                    attributes,
+                    namespace: scope,
                 }
             }
             xml::writer::XmlEvent::EndElement { name } => {
+                self.scopes.pop();
                 xml::reader::XmlEvent::EndElement {
-                    name: match name {
-                        Some(n) => n.to_owned(),
-                        None => unreachable!()
+                    // The open element's name has its namespace resolved, so is preferred
+                    name: match self.open.pop().or(name.map(|n| n.to_owned())) {
+                        Some(n) => n,
+                        None => return Err(xml::writer::Error::EndElementNameIsNotSpecified)
                     },
                 }
             }
             xml::writer::XmlEvent::CData(s) => {
-                xml::reader::XmlEvent::CData(s.into())
+                xml::reader::XmlEvent::CData(Self::unescape(s).into_owned())
             }
             xml::writer::XmlEvent::Characters(s) => {
-                xml::reader::XmlEvent::Characters(s.into())
+                xml::reader::XmlEvent::Characters(Self::unescape(s).into_owned())
             }
             xml::writer::XmlEvent::Comment(s) => {
                 xml::reader::XmlEvent::Comment(s.into())
             }
         };
-        self.0.push(re);
+        self.events.push(re);
         Ok(())
     }
 }
 
+/// Line ending written between lines of indented output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+  #[default]
+  Lf,
+  CrLf,
+}
+
+impl LineEnding {
+  fn as_str(self) -> &'static str {
+    match self {
+      LineEnding::Lf => "\n",
+      LineEnding::CrLf => "\r\n",
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Options {
-  pub include_schema_location: bool
+  pub include_schema_location: bool,
+  pub indent_size: usize,
+  pub hoist_namespaces: bool,
+  pub absent_as_empty_element: bool,
+  pub write_document_declaration: bool,
+  pub unit_variant_as_element: bool,
+  pub line_ending: LineEnding,
+  pub include_end_document: bool,
+  pub newtype_struct_as_wrapper: bool,
+  pub default_namespace: Option<String>,
+  pub value_first: bool,
+  pub pad_self_closing: bool,
 }
 
 impl Default for Options {
   fn default() -> Self {
     Self {
-      include_schema_location: true
+      include_schema_location: true,
+      indent_size: 2,
+      hoist_namespaces: false,
+      absent_as_empty_element: false,
+      write_document_declaration: true,
+      unit_variant_as_element: false,
+      line_ending: LineEnding::Lf,
+      include_end_document: true,
+      newtype_struct_as_wrapper: false,
+      default_namespace: None,
+      value_first: false,
+      pad_self_closing: false,
     }
   }
 }
 
+impl Options {
+  /// Start building a set of options, from the default values
+  ///
+  /// ```
+  /// let options = xml_serde::Options::builder()
+  ///     .include_schema_location(false)
+  ///     .indent_size(4)
+  ///     .build();
+  /// assert!(!options.include_schema_location);
+  /// assert_eq!(options.indent_size, 4);
+  /// ```
+  pub fn builder() -> OptionsBuilder {
+    OptionsBuilder::default()
+  }
+}
+
+/// Builder for `Options`, see `Options::builder`
+#[derive(Clone, Debug, Default)]
+pub struct OptionsBuilder {
+  options: Options,
+}
+
+impl OptionsBuilder {
+  /// Whether to emit `xsi:schemaLocation` attributes for namespaced elements
+  pub fn include_schema_location(mut self, v: bool) -> Self {
+    self.options.include_schema_location = v;
+    self
+  }
+
+  /// Number of spaces to indent each level of nested elements by
+  pub fn indent_size(mut self, n: usize) -> Self {
+    self.options.indent_size = n;
+    self
+  }
+
+  /// Whether to declare every prefixed namespace used in the document on the root element
+  pub fn hoist_namespaces(mut self, v: bool) -> Self {
+    self.options.hoist_namespaces = v;
+    self
+  }
+
+  /// Whether to write `None` values as empty elements, rather than leaving them out
+  pub fn absent_as_empty_element(mut self, v: bool) -> Self {
+    self.options.absent_as_empty_element = v;
+    self
+  }
+
+  /// Whether to start the output with an `<?xml ...?>` declaration
+  pub fn write_document_declaration(mut self, v: bool) -> Self {
+    self.options.write_document_declaration = v;
+    self
+  }
+
+  /// Whether to write unit enum variants as empty elements named for the variant, rather than
+  /// as text
+  pub fn unit_variant_as_element(mut self, v: bool) -> Self {
+    self.options.unit_variant_as_element = v;
+    self
+  }
+
+  /// Line ending to use in the output
+  pub fn line_ending(mut self, v: LineEnding) -> Self {
+    self.options.line_ending = v;
+    self
+  }
+
+  /// Whether to finish the output of `to_events_custom` with an `EndDocument` event
+  pub fn include_end_document(mut self, v: bool) -> Self {
+    self.options.include_end_document = v;
+    self
+  }
+
+  /// Whether to wrap the contents of newtype structs in an element named for the struct, rather
+  /// than writing them as if they were the wrapped value
+  pub fn newtype_struct_as_wrapper(mut self, v: bool) -> Self {
+    self.options.newtype_struct_as_wrapper = v;
+    self
+  }
+
+  /// Namespace for elements named without one, declared as the default namespace
+  pub fn default_namespace(mut self, v: impl Into<String>) -> Self {
+    self.options.default_namespace = Some(v.into());
+    self
+  }
+
+  /// Whether to write the text of an element (`$value`, `$valueRaw` and `$list` fields) before
+  /// its child elements, whatever order the fields are declared in
+  pub fn value_first(mut self, v: bool) -> Self {
+    self.options.value_first = v;
+    self
+  }
+
+  /// Whether to write a space before the end of self closing elements, as `<foo />`
+  pub fn pad_self_closing(mut self, v: bool) -> Self {
+    self.options.pad_self_closing = v;
+    self
+  }
+
+  pub fn build(self) -> Options {
+    self.options
+  }
+}
+
 /// Serialise serde item to XML
 ///
 /// # Arguments
@@ -103,26 +320,159 @@ pub fn to_string_custom<T>(value: &T, options: Options) -> Result<String, crate:
     where
         T: Serialize,
 {
+    let mut serializer = Serializer;
+    let val = value.serialize(&mut serializer)?;
+    data_to_string(&val, &options)
+}
+
+fn data_to_string(val: &SerializerData, options: &Options) -> Result<String, crate::Error> {
+    let output = Vec::with_capacity(estimate_output_size(val));
+    let output = write_data(output, val, options)?;
+    String::from_utf8(output).map_err(|_| crate::Error::Message("invalid UTF-8".into()))
+}
+
+/// Format serialized data as XML, straight into `output`
+fn create_writer<W: std::io::Write>(output: W, options: &Options) -> xml::writer::EventWriter<W> {
     let mut conf = xml::writer::EmitterConfig::new()
         .perform_indent(true)
-        .write_document_declaration(true)
+        .write_document_declaration(options.write_document_declaration)
         .normalize_empty_elements(true)
         .cdata_to_characters(true)
         .keep_element_names_stack(true)
-        .pad_self_closing(false);
+        .pad_self_closing(options.pad_self_closing)
+        .indent_string(" ".repeat(options.indent_size))
+        .line_separator(options.line_ending.as_str());
     conf.perform_escaping = false;
+    conf.create_writer(output)
+}
+
+fn write_data<W: std::io::Write>(output: W, val: &SerializerData, options: &Options) -> Result<W, crate::Error> {
+    let mut writer = create_writer(output, options);
+    let mut state = SerializerState::new(val, options);
+    format_data(&mut writer, val, &mut state)?;
+    Ok(writer.into_inner())
+}
+
+/// Bytes written for an element besides its name and contents: angle brackets, indentation and
+/// line breaks
+const ELEMENT_OVERHEAD: usize = 16;
+
+/// A rough guess at the length of the XML for some data, to size the output buffer with
+fn estimate_output_size(data: &SerializerData) -> usize {
+    match data {
+        SerializerData::CData(s) | SerializerData::String(s) => s.len(),
+        SerializerData::Seq(s) => s.iter().map(estimate_output_size).sum(),
+        SerializerData::Absent => 0,
+        SerializerData::UnitVariant(v) => v.len(),
+        SerializerData::Newtype(_, d) => estimate_output_size(d),
+        SerializerData::Struct { attrs, contents } => {
+            let attrs_size: usize = attrs.iter().map(|(k, v)| k.len() + v.len() + 4).sum();
+            let contents_size: usize = contents.iter().map(|(k, v)| {
+                let elements = match v {
+                    SerializerData::Seq(s) => s.len(),
+                    _ => 1,
+                };
+                elements * (2 * k.len() + ELEMENT_OVERHEAD) + estimate_output_size(v)
+            }).sum();
+            attrs_size + contents_size
+        }
+    }
+}
+
+/// Serialise serde item to an XML fragment, without the document declaration
+///
+/// # Arguments
+/// * `value` - The value to be serialised
+pub fn to_fragment_string<T>(value: &T) -> Result<String, crate::Error>
+    where
+        T: Serialize,
+{
+    to_string_custom(value, Options {
+        write_document_declaration: false,
+        ..Options::default()
+    })
+}
+
+/// Serialise serde item to XML, writing the output to an `std::io::Write`
+///
+/// Any writer can be used, such as a `BufWriter` or a compressor; pass it by mutable reference to
+/// keep hold of it, for example to flush or finish it afterwards.
+///
+/// ```
+/// use std::io::Write;
+///
+/// #[derive(serde_derive::Serialize)]
+/// struct Foo {
+///     foo: String,
+/// }
+///
+/// let mut writer = std::io::BufWriter::new(Vec::new());
+/// xml_serde::to_writer(&mut writer, &Foo { foo: "bar".to_string() }).unwrap();
+/// writer.flush().unwrap();
+/// assert!(writer.get_ref().ends_with(b">bar</foo>"));
+/// ```
+///
+/// # Arguments
+/// * `writer` - The destination for the XML output
+/// * `value` - The value to be serialised
+pub fn to_writer<W, T>(writer: W, value: &T) -> Result<(), crate::Error>
+    where
+        W: std::io::Write,
+        T: Serialize,
+{
+    to_writer_custom(writer, value, Options::default())
+}
 
-    let c = std::io::Cursor::new(Vec::new());
-    let mut writer = EmitterWriter(conf.create_writer(c));
+/// Serialise serde item to XML, writing the output to an `std::io::Write`, with custom options
+///
+/// Unless `value_first` is set, elements are written as they're serialized, so large sequences
+/// aren't held in memory. The value is serialized twice: first to find the namespaces and schema
+/// locations used and check for errors, then to write it out. Any fields before the last attribute
+/// of a struct are held back until the start of its element has been written.
+///
+/// # Arguments
+/// * `writer` - The destination for the XML output
+/// * `value` - The value to be serialised
+/// * `options` - Custom options for the serializer
+pub fn to_writer_custom<W, T>(writer: W, value: &T, options: Options) -> Result<(), crate::Error>
+    where
+        W: std::io::Write,
+        T: Serialize,
+{
     let mut serializer = Serializer;
-    let val = value.serialize(&mut serializer)?;
-    let mut state = _SerializerState {
-        raw_output: false,
-        ns_stack: vec![],
-        include_schema_location: options.include_schema_location,
+    // Fields can only be reordered once they've all been serialized
+    if options.value_first {
+        let val = value.serialize(&mut serializer)?;
+        write_data(writer, &val, &options)?;
+        return Ok(());
+    }
+    let mut plan = StreamPlan {
+        first_pass: true,
+        ..StreamPlan::default()
     };
-    format_data(&mut writer, &val, &mut state)?;
-    Ok(String::from_utf8(writer.0.into_inner().into_inner()).unwrap())
+    let mut state = SerializerState::with_tags(&[], &options);
+    state.recorded_tags = Some(vec![]);
+    value.serialize(StreamSerializer {
+        writer: &mut NullWriter,
+        state: &mut state,
+        buffer: &mut serializer,
+        plan: &mut plan,
+        target: StreamTarget::Contents,
+    })?;
+
+    let tags = state.recorded_tags.unwrap_or_default();
+    let tags = tags.iter().map(|tag| Tag::new(tag)).collect::<Vec<_>>();
+    let mut state = SerializerState::with_tags(&tags, &options);
+    plan.first_pass = false;
+    plan.structs = 0;
+    let mut writer = create_writer(writer, &options);
+    value.serialize(StreamSerializer {
+        writer: &mut writer,
+        state: &mut state,
+        buffer: &mut serializer,
+        plan: &mut plan,
+        target: StreamTarget::Contents,
+    })
 }
 
 /// Serialise serde item to a list of XML events
@@ -145,202 +495,457 @@ pub fn to_events_custom<T>(value: &T, options: Options) -> Result<Vec<xml::reade
     where
         T: Serialize,
 {
-    let mut writer = ListWriter(vec![]);
+    let mut writer = ListWriter::default();
+    if options.write_document_declaration {
+        writer.events.push(xml::reader::XmlEvent::StartDocument {
+            version: xml::common::XmlVersion::Version10,
+            encoding: "UTF-8".to_string(),
+            standalone: None,
+        });
+    }
     let mut serializer = Serializer;
     let val = value.serialize(&mut serializer)?;
-    let mut state = _SerializerState {
-        raw_output: false,
-        ns_stack: vec![],
-        include_schema_location: options.include_schema_location,
-    };
+    let mut state = SerializerState::new(&val, &options);
     format_data(&mut writer, &val, &mut state)?;
-    Ok(writer.0)
+    if options.include_end_document {
+        writer.events.push(xml::reader::XmlEvent::EndDocument);
+    }
+    Ok(writer.events)
 }
 
+/// The intermediate tree of data produced by `Serializer`, before it's written out as XML
+///
+/// More variants may be added, so matches on this need a wildcard arm.
 #[derive(Debug)]
-pub enum _SerializerData {
+#[non_exhaustive]
+pub enum SerializerData {
     CData(String),
     String(String),
-    Seq(Vec<_SerializerData>),
-    Struct { attrs: Vec<(Cow<'static, str>, String)>, contents: Vec<(Cow<'static, str>, _SerializerData)> },
+    Seq(Vec<SerializerData>),
+    /// A `None` value
+    Absent,
+    /// A unit enum variant, written either as text or as an empty element
+    UnitVariant(&'static str),
+    /// A newtype struct, written either as just its contents or wrapped in an element named for
+    /// the struct
+    Newtype(&'static str, Box<SerializerData>),
+    /// The contents of an element; each entry in `contents` is named with a tag in the
+    /// `{namespace}prefix:tag-name` format, or one of the special `$value` names
+    Struct { attrs: Vec<(Cow<'static, str>, String)>, contents: Vec<(Cow<'static, str>, SerializerData)> },
 }
 
-impl _SerializerData {
+impl SerializerData {
+    /// Text content
+    pub fn text(s: impl Into<String>) -> Self {
+        SerializerData::String(s.into())
+    }
+
+    /// A single element, with the given attributes and child elements
+    ///
+    /// ```
+    /// use xml_serde::{SerializerData, SerializerState, Options};
+    ///
+    /// let data = SerializerData::element("{urn:foo}foo:bar", vec![("id".to_string(), "1".to_string())], vec![
+    ///     ("$value".to_string(), SerializerData::text("a & b")),
+    /// ]);
+    /// let mut out = vec![];
+    /// let mut config = xml::writer::EmitterConfig::new().write_document_declaration(false);
+    /// config.perform_escaping = false;
+    /// let mut writer = config.create_writer(&mut out);
+    /// let mut state = SerializerState::new(&data, &Options::builder().include_schema_location(false).build());
+    /// xml_serde::format_data(&mut writer, &data, &mut state).unwrap();
+    /// assert_eq!(String::from_utf8(out).unwrap(), r#"<foo:bar xmlns:foo="urn:foo" id="1">a &amp; b</foo:bar>"#);
+    /// ```
+    pub fn element(tag: &str, attrs: Vec<(String, String)>, children: Vec<(String, SerializerData)>) -> Self {
+        SerializerData::Struct {
+            attrs: vec![],
+            contents: vec![(tag.to_string().into(), SerializerData::Struct {
+                attrs: attrs.into_iter().map(|(k, v)| (k.into(), v)).collect(),
+                contents: children.into_iter().map(|(k, v)| (k.into(), v)).collect(),
+            })],
+        }
+    }
+
     fn as_str(&self) -> String {
         match self {
-            _SerializerData::CData(s) => s.clone(),
-            _SerializerData::String(s) => s.clone(),
-            _SerializerData::Seq(s) => s.iter().map(|d| d.as_str()).collect::<Vec<_>>().join(","),
-            _SerializerData::Struct { contents, .. } => contents.iter().map(|(_, d)| d.as_str()).collect::<Vec<_>>().join(","),
+            SerializerData::CData(s) => s.clone(),
+            SerializerData::String(s) => s.clone(),
+            SerializerData::Seq(s) => s.iter().map(|d| d.as_str()).collect::<Vec<_>>().join(","),
+            SerializerData::Absent => String::new(),
+            SerializerData::UnitVariant(v) => v.to_string(),
+            SerializerData::Newtype(_, d) => d.as_str(),
+            SerializerData::Struct { contents, .. } => contents.iter().map(|(_, d)| d.as_str()).collect::<Vec<_>>().join(","),
+        }
+    }
+
+    /// The data within any newtype structs wrapping this
+    fn unwrap_newtypes(&self) -> &Self {
+        match self {
+            SerializerData::Newtype(_, d) => d.unwrap_newtypes(),
+            d => d
+        }
+    }
+
+    /// Convert a serialized map into a list of attributes, for the `$attr_map` field
+    fn into_attrs(self) -> Result<Vec<(Cow<'static, str>, String)>, crate::Error> {
+        match self {
+            SerializerData::Struct { contents, .. } => Ok(contents.into_iter()
+                .filter(|(_, v)| !matches!(v, SerializerData::Absent))
+                .map(|(k, v)| (k, v.as_str()))
+                .collect()),
+            SerializerData::Newtype(_, d) => d.into_attrs(),
+            _ => Err(crate::Error::Unsupported)
         }
     }
 }
 
-struct _SerializerState {
+/// Write out a data tree as XML, with the default options, as `to_string` would
+impl TryFrom<SerializerData> for String {
+    type Error = crate::Error;
+
+    fn try_from(val: SerializerData) -> Result<String, crate::Error> {
+        data_to_string(&val, &Options::default())
+    }
+}
+
+/// State carried through `format_data` while writing out a document
+pub struct SerializerState {
     raw_output: bool,
-    ns_stack: Vec<String>,
     include_schema_location: bool,
+    schema_location: String,
+    hoisted_namespaces: Vec<(Option<String>, String)>,
+    root_emitted: bool,
+    absent_as_empty_element: bool,
+    unit_variant_as_element: bool,
+    default_namespace: Option<String>,
+    value_first: bool,
+    newtype_struct_as_wrapper: bool,
+    /// Tags of the elements written so far, for the first pass of `to_writer_custom`
+    recorded_tags: Option<Vec<String>>,
+}
+
+impl SerializerState {
+    /// Set up the state for writing out `val` as a whole document
+    pub fn new(val: &SerializerData, options: &Options) -> Self {
+        Self::with_tags(&collect_tags(val, options), options)
+    }
+
+    /// Set up the state for writing out a document with elements of these tags, in document order
+    fn with_tags(tags: &[Tag], options: &Options) -> Self {
+        let mut hoisted_namespaces: Vec<(Option<String>, String)> = vec![];
+        if options.hoist_namespaces {
+            for tag in tags {
+                // A default namespace on the root would take in every element without a namespace,
+                // so only prefixed ones are hoisted. A prefix can only be bound once on the root,
+                // any later conflicting uses are left to be declared where they're used
+                let (p, n) = match (tag.p, tag.n) {
+                    (Some(p), Some(n)) => (p, n),
+                    _ => continue
+                };
+                if !hoisted_namespaces.iter().any(|(hp, _)| hp.as_deref() == Some(p)) {
+                    hoisted_namespaces.push((Some(p.into()), n.to_string()));
+                }
+            }
+        }
+        // Schema locations for all namespaces used are given together on the root element
+        let mut schema_locations: Vec<(&str, String)> = vec![];
+        if options.include_schema_location {
+            for tag in tags {
+                let n = match tag.n {
+                    Some(n) => n,
+                    None => continue
+                };
+                if schema_locations.iter().any(|(ln, _)| *ln == n) {
+                    continue;
+                }
+                match tag.l {
+                    Some("") => {}
+                    Some(l) => schema_locations.push((n, format!("{} {}", n, l))),
+                    None => {
+                        let last_n = n.rsplit(':').next().unwrap();
+                        schema_locations.push((n, format!("{} {}.xsd", n, last_n)));
+                    }
+                }
+            }
+        }
+        SerializerState {
+            raw_output: false,
+            include_schema_location: options.include_schema_location,
+            schema_location: schema_locations.into_iter().map(|(_, l)| l).collect::<Vec<_>>().join(" "),
+            hoisted_namespaces,
+            root_emitted: false,
+            absent_as_empty_element: options.absent_as_empty_element,
+            unit_variant_as_element: options.unit_variant_as_element,
+            default_namespace: options.default_namespace.clone(),
+            value_first: options.value_first,
+            newtype_struct_as_wrapper: options.newtype_struct_as_wrapper,
+            recorded_tags: None,
+        }
+    }
+
+    /// Note the tag of an element about to be written, if tags are being recorded
+    fn record_tag(&mut self, tag: &Tag) {
+        if let Some(tags) = &mut self.recorded_tags {
+            let tag = tag.to_tag_string();
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+    }
+
+    /// The data to write within an element, which is the contents of a newtype struct unless
+    /// those are wrapped in their own element
+    fn element_data<'a>(&self, d: &'a SerializerData) -> &'a SerializerData {
+        if self.newtype_struct_as_wrapper {
+            d
+        } else {
+            d.unwrap_newtypes()
+        }
+    }
+
+    fn is_hoisted(&self, p: Option<&str>, n: &str) -> bool {
+        self.hoisted_namespaces.iter().any(|(hp, hn)| hp.as_deref() == p && hn == n)
+    }
+}
+
+/// Find the tags of all elements in the data tree, in document order
+fn collect_tags<'a>(data: &'a SerializerData, options: &Options) -> Vec<Tag<'a>> {
+    let mut tags = vec![];
+    match data {
+        SerializerData::Seq(s) => {
+            for d in s {
+                tags.extend(collect_tags(d, options));
+            }
+        }
+        SerializerData::Struct { contents, .. } => {
+            for (tag, d) in contents {
+                let d = if options.newtype_struct_as_wrapper { d } else { d.unwrap_newtypes() };
+                if matches!(d, SerializerData::Absent) && !options.absent_as_empty_element {
+                    continue;
+                }
+                // Empty tags are reported as invalid when the element is written
+                if !tag.is_empty() && !tag.starts_with('$') {
+                    tags.push(Tag::from(tag));
+                }
+                tags.extend(collect_tags(d, options));
+            }
+        }
+        SerializerData::UnitVariant(v) if options.unit_variant_as_element => tags.push(Tag::from_static(v)),
+        SerializerData::Newtype(n, d) => {
+            if options.newtype_struct_as_wrapper {
+                tags.push(Tag::from_static(n));
+            }
+            tags.extend(collect_tags(d, options));
+        }
+        SerializerData::CData(_) | SerializerData::String(_) | SerializerData::Absent |
+        SerializerData::UnitVariant(_) => {}
+    }
+    tags
+}
+
+const XSI_NAMESPACE: &str = "http://www.w3.org/2001/XMLSchema-instance";
+
+/// Names and escaped values of attributes, ready to go to a writer with `perform_escaping` off
+pub(crate) fn attrs_to_xml_names<'a>(attrs: &'a [(Cow<'static, str>, String)]) -> Vec<(xml::name::Name<'a>, Cow<'a, str>)> {
+    attrs.iter().map(|(attr_k, attr_v)| {
+        let mut name = xml::name::Name::from(Tag::from(attr_k));
+        // The xml prefix is always bound to its namespace, and needs no declaration
+        if name.prefix.is_none() && name.namespace == Some(xml::namespace::NS_XML_URI) {
+            name.prefix = Some(xml::namespace::NS_XML_PREFIX);
+        }
+        // An attribute without a prefix is in no namespace, so schema instance attributes get the
+        // conventional one
+        if name.prefix.is_none() && name.namespace == Some(XSI_NAMESPACE) {
+            name.prefix = Some("xsi");
+        }
+        (name, xml::escape::escape_str_attribute(attr_v))
+    }).collect()
+}
+
+/// Check that the prefix and local name of a tag are valid XML names (NCNames)
+pub(crate) fn validate_xml_name(name: &str) -> crate::Result<()> {
+    if !name.is_empty() && Tag::new(name).has_valid_name() {
+        Ok(())
+    } else {
+        Err(crate::Error::InvalidElementName(name.to_string()))
+    }
+}
+
+/// Adds the namespace declarations, and on the root element the schema location, to an element
+fn configure_element<'a>(
+    mut elm: xml::writer::events::StartElementBuilder<'a>, state: &'a SerializerState, tag: &Tag<'a>, is_root: bool,
+) -> xml::writer::events::StartElementBuilder<'a> {
+    if is_root {
+        for (p, n) in &state.hoisted_namespaces {
+            match p {
+                Some(p) => elm = elm.ns(p, n),
+                None => elm = elm.default_ns(n)
+            };
+        }
+    }
+    // Declaring xsi on the root puts it in scope for the whole document
+    if is_root && state.include_schema_location {
+        elm = elm.ns("xsi", XSI_NAMESPACE);
+    }
+    if let Some(n) = tag.n {
+        if !state.is_hoisted(tag.p, n) {
+            match tag.p {
+                Some(p) => elm = elm.ns(p, n),
+                None => elm = elm.default_ns(n)
+            };
+        }
+    } else if let (None, Some(n)) = (tag.p, &state.default_namespace) {
+        elm = elm.default_ns(n.as_str());
+    }
+    if is_root && state.include_schema_location && !state.schema_location.is_empty() {
+        elm = elm.attr(xml::name::Name {
+            namespace: None,
+            local_name: "schemaLocation",
+            prefix: Some("xsi"),
+        }, &state.schema_location);
+    }
+    elm
 }
 
-fn format_data<W: EventWriter>(writer: &mut W, val: &_SerializerData, state: &mut _SerializerState) -> Result<(), crate::Error> {
+/// Write out serialized data as XML events
+///
+/// Text and attribute values are escaped here, so an xml-rs writer should be set up with
+/// `perform_escaping` off.
+pub fn format_data(writer: &mut dyn EventWriter, val: &SerializerData, state: &mut SerializerState) -> Result<(), crate::Error> {
     match val {
-        _SerializerData::CData(s) => {
+        SerializerData::CData(s) => {
             writer.write(xml::writer::XmlEvent::cdata(&match state.raw_output {
                 true => s.to_string(),
                 false => xml::escape::escape_str_pcdata(s).to_string()
             }))?
         }
-        _SerializerData::String(s) => {
+        SerializerData::String(s) => {
             writer.write(xml::writer::XmlEvent::characters(&match state.raw_output {
                 true => s.to_string(),
                 false => xml::escape::escape_str_pcdata(s).to_string()
             }))?
         }
-        _SerializerData::Seq(s) => {
+        SerializerData::Seq(s) => {
             for d in s {
-                format_data(writer, &d, state)?;
+                format_data(writer, d, state)?;
             }
         }
-        _SerializerData::Struct {
+        SerializerData::Absent => {}
+        SerializerData::UnitVariant(v) => if state.unit_variant_as_element {
+            format_data(writer, &SerializerData::Struct {
+                attrs: vec![],
+                contents: vec![(Cow::Borrowed(*v), SerializerData::Struct {
+                    attrs: vec![],
+                    contents: vec![],
+                })],
+            }, state)?
+        } else {
+            format_data(writer, &SerializerData::String(v.to_string()), state)?
+        }
+        SerializerData::Struct {
             contents,
             ..
         } => {
-            for (tag, d) in contents {
-                if *tag == "$valueRaw" {
-                    let old_val = state.raw_output;
-                    state.raw_output = true;
-                    format_data(writer, &d, state)?;
-                    state.raw_output = old_val;
-                } else if tag.starts_with(&"$value") {
-                    format_data(writer, &d, state)?;
-                } else {
-                    let parsed_tag = Tag::from_cow(&tag);
-                    let base_name = parsed_tag.e;
-                    let name = match parsed_tag.p {
-                        Some(p) => format!("{}:{}", p, base_name),
-                        None => base_name.to_string()
-                    };
-
-                    match d {
-                        _SerializerData::Seq(s) => {
-                            for d in s {
-                                let attrs = match d {
-                                    _SerializerData::Struct {
-                                        attrs,
-                                        ..
-                                    } => attrs.to_owned(),
-                                    _ => vec![]
-                                };
-                                let attrs = attrs.iter().map(|(attr_k, attr_v)| {
-                                    (xml::name::Name::from(Tag::from_cow(attr_k)), attr_v)
-                                }).collect::<Vec<_>>();
-                                let mut elm = xml::writer::XmlEvent::start_element(name.as_str());
-                                if state.include_schema_location {
-                                    elm = elm.ns("xsi", "http://www.w3.org/2001/XMLSchema-instance");
-                                }
-                                let mut loc = String::new();
-                                let mut should_pop = false;
-                                if let Some(n) = parsed_tag.n {
-                                    match parsed_tag.p {
-                                        Some(p) => elm = elm.ns(p, n),
-                                        None => elm = elm.default_ns(n)
-                                    };
-                                    if !state.ns_stack.iter().any(|ns| ns == n) {
-                                        if let Some(l) = parsed_tag.l {
-                                            if !l.is_empty() {
-                                                loc.push_str(&format!("{} {}", n, l));
-                                            }
-                                        } else {
-                                            let last_n = n.rsplit(':').next().unwrap();
-                                            loc.push_str(&format!("{} {}.xsd", n, last_n));
-                                        }
-                                        if state.include_schema_location && !loc.is_empty() {
-                                            elm = elm.attr(xml::name::Name {
-                                                namespace: None,
-                                                local_name: "schemaLocation",
-                                                prefix: Some("xsi"),
-                                            }, &loc);
-                                        }
-                                        state.ns_stack.push(n.to_string());
-                                        should_pop = true;
-                                    }
-                                }
-                                for (name, attr_v) in attrs.clone() {
-                                    elm = elm.attr(name, attr_v);
-                                }
-
-                                writer.write(elm)?;
-                                format_data(writer, &d, state)?;
-                                writer.write(xml::writer::XmlEvent::end_element())?;
-                                if should_pop {
-                                    state.ns_stack.pop();
-                                }
-                            }
-                        }
-                        d => {
-                            let attrs = match d {
-                                _SerializerData::Struct {
-                                    attrs,
-                                    ..
-                                } => attrs.to_owned(),
-                                _ => vec![]
-                            };
-                            let attrs = attrs.iter().map(|(attr_k, attr_v)| {
-                                (xml::name::Name::from(Tag::from_cow(attr_k)), attr_v)
-                            }).collect::<Vec<_>>();
-
-                            let mut elm = xml::writer::XmlEvent::start_element(name.as_str());
-                            if state.include_schema_location {
-                                elm = elm.ns("xsi", "http://www.w3.org/2001/XMLSchema-instance");
-                            }
-                            let mut loc = String::new();
-                            let mut should_pop = false;
-                            if let Some(n) = parsed_tag.n {
-                                match parsed_tag.p {
-                                    Some(p) => elm = elm.ns(p, n),
-                                    None => elm = elm.default_ns(n)
-                                };
-                                if !state.ns_stack.iter().any(|ns| ns == n) {
-                                    if let Some(l) = parsed_tag.l {
-                                        if !l.is_empty() {
-                                            loc.push_str(&format!("{} {}", n, l));
-                                        }
-                                    } else {
-                                        let last_n = n.rsplit(':').next().unwrap();
-                                        loc.push_str(&format!("{} {}.xsd", n, last_n));
-                                    }
-                                    if state.include_schema_location && !loc.is_empty() {
-                                        elm = elm.attr(xml::name::Name {
-                                            namespace: None,
-                                            local_name: "schemaLocation",
-                                            prefix: Some("xsi"),
-                                        }, &loc);
-                                    }
-                                    state.ns_stack.push(n.to_string());
-                                    should_pop = true;
-                                }
-                            }
-                            for (name, attr_v) in attrs {
-                                elm = elm.attr(name, attr_v);
-                            }
-
-                            writer.write(elm)?;
-                            format_data(writer, &d, state)?;
-                            writer.write(xml::writer::XmlEvent::end_element())?;
-                            if should_pop {
-                                state.ns_stack.pop();
-                            }
-                        }
-                    };
-                }
+            let is_text = |tag: &Cow<'static, str>| tag.starts_with("$value") || *tag == "$list";
+            let contents: Vec<_> = if state.value_first {
+                contents.iter().filter(|(tag, _)| is_text(tag))
+                    .chain(contents.iter().filter(|(tag, _)| !is_text(tag)))
+                    .collect()
+            } else {
+                contents.iter().collect()
+            };
+            for field in contents {
+                format_field(writer, field, state)?;
+            }
+        }
+        SerializerData::Newtype(n, d) => if state.newtype_struct_as_wrapper {
+            state.record_tag(&Tag::from_static(n));
+            format_element(writer, &Tag::from_static(n), d, state)?
+        } else {
+            format_data(writer, d, state)?
+        }
+    }
+    Ok(())
+}
+
+/// Write out a field of a struct, as text for the `$value` fields and elements for the others
+fn format_field(writer: &mut dyn EventWriter, (tag, d): &(Cow<'static, str>, SerializerData), state: &mut SerializerState) -> Result<(), crate::Error> {
+    if *tag == "$valueRaw" {
+        let old_val = state.raw_output;
+        state.raw_output = true;
+        format_data(writer, d, state)?;
+        state.raw_output = old_val;
+    } else if tag.starts_with("$value") {
+        format_data(writer, d, state)?;
+    } else if *tag == "$list" {
+        let items = match d {
+            SerializerData::Seq(s) => s.iter().map(|d| d.as_str()).collect::<Vec<_>>(),
+            d => vec![d.as_str()]
+        };
+        format_data(writer, &SerializerData::String(items.join(" ")), state)?;
+    } else {
+        validate_xml_name(tag)?;
+        format_element(writer, &Tag::from(tag), d, state)?;
+    }
+    Ok(())
+}
+
+/// Write out `d` as elements named `parsed_tag`, one for each item if it's a sequence
+fn format_element(writer: &mut dyn EventWriter, parsed_tag: &Tag, d: &SerializerData, state: &mut SerializerState) -> Result<(), crate::Error> {
+    if !matches!(state.element_data(d), SerializerData::Absent) || state.absent_as_empty_element {
+        state.record_tag(parsed_tag);
+    }
+    let items = match state.element_data(d) {
+        SerializerData::Seq(s) => s.as_slice(),
+        d => std::slice::from_ref(d)
+    };
+    for d in items {
+        let d = state.element_data(d);
+        if matches!(d, SerializerData::Absent) && !state.absent_as_empty_element {
+            continue;
+        }
+        let attrs = match d {
+            SerializerData::Struct {
+                attrs,
+                ..
+            } => attrs.as_slice(),
+            _ => &[]
+        };
+        start_element(writer, parsed_tag, attrs, state)?;
+        format_data(writer, d, state)?;
+        writer.write(xml::writer::XmlEvent::end_element().into())?;
+    }
+    Ok(())
+}
+
+/// Write the start of an element, along with its attributes and any namespace declarations
+fn start_element(writer: &mut dyn EventWriter, parsed_tag: &Tag, attrs: &[(Cow<'static, str>, String)], state: &mut SerializerState) -> Result<(), crate::Error> {
+    let name = match parsed_tag.p {
+        Some(p) => format!("{}:{}", p, parsed_tag.e),
+        None => parsed_tag.e.to_string()
+    };
+    let is_root = !std::mem::replace(&mut state.root_emitted, true);
+    for (attr_k, _) in attrs {
+        validate_xml_name(attr_k)?;
+    }
+    let attrs = attrs_to_xml_names(attrs);
+    let mut elm = configure_element(
+        xml::writer::XmlEvent::start_element(name.as_str()), state, parsed_tag, is_root,
+    );
+    for (name, attr_v) in &attrs {
+        if let (Some(p), Some(n)) = (name.prefix, name.namespace) {
+            if p != xml::namespace::NS_XML_PREFIX && !state.is_hoisted(Some(p), n) {
+                elm = elm.ns(p, n);
             }
         }
+        elm = elm.attr(*name, attr_v);
     }
+    writer.write(elm.into())?;
     Ok(())
 }
 
 impl<'a> ser::Serializer for &'a mut Serializer {
-    type Ok = _SerializerData;
+    type Ok = SerializerData;
     type Error = crate::Error;
     type SerializeSeq = SeqSerializer<'a>;
     type SerializeTuple = SeqSerializer<'a>;
@@ -350,79 +955,100 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     type SerializeStruct = StructSerializer<'a>;
     type SerializeStructVariant = StructVariantSerializer<'a>;
 
-    fn serialize_bool(self, v: bool) -> Result<_SerializerData, Self::Error> {
+    fn serialize_bool(self, v: bool) -> Result<SerializerData, Self::Error> {
         let val = if v { "true" } else { "false" };
-        Ok(_SerializerData::String(val.to_string()))
+        Ok(SerializerData::String(val.to_string()))
     }
 
-    fn serialize_i8(self, v: i8) -> Result<_SerializerData, Self::Error> {
+    fn serialize_i8(self, v: i8) -> Result<SerializerData, Self::Error> {
         self.serialize_i64(i64::from(v))
     }
 
-    fn serialize_i16(self, v: i16) -> Result<_SerializerData, Self::Error> {
+    fn serialize_i16(self, v: i16) -> Result<SerializerData, Self::Error> {
         self.serialize_i64(i64::from(v))
     }
 
-    fn serialize_i32(self, v: i32) -> Result<_SerializerData, Self::Error> {
+    fn serialize_i32(self, v: i32) -> Result<SerializerData, Self::Error> {
         self.serialize_i64(i64::from(v))
     }
 
-    fn serialize_i64(self, v: i64) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::String(v.to_string()))
+    fn serialize_i64(self, v: i64) -> Result<SerializerData, Self::Error> {
+        Ok(SerializerData::String(v.to_string()))
     }
 
-    fn serialize_u8(self, v: u8) -> Result<_SerializerData, Self::Error> {
+    fn serialize_u8(self, v: u8) -> Result<SerializerData, Self::Error> {
         self.serialize_u64(u64::from(v))
     }
 
-    fn serialize_u16(self, v: u16) -> Result<_SerializerData, Self::Error> {
+    fn serialize_u16(self, v: u16) -> Result<SerializerData, Self::Error> {
         self.serialize_u64(u64::from(v))
     }
 
-    fn serialize_u32(self, v: u32) -> Result<_SerializerData, Self::Error> {
+    fn serialize_u32(self, v: u32) -> Result<SerializerData, Self::Error> {
         self.serialize_u64(u64::from(v))
     }
 
-    fn serialize_u64(self, v: u64) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::String(v.to_string()))
+    fn serialize_u64(self, v: u64) -> Result<SerializerData, Self::Error> {
+        Ok(SerializerData::String(v.to_string()))
     }
 
-    fn serialize_f32(self, v: f32) -> Result<_SerializerData, Self::Error> {
+    fn serialize_f32(self, v: f32) -> Result<SerializerData, Self::Error> {
         self.serialize_f64(f64::from(v))
     }
 
-    fn serialize_f64(self, v: f64) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::String(v.to_string()))
+    fn serialize_f64(self, v: f64) -> Result<SerializerData, Self::Error> {
+        // Special values are written as XSD has them, rather than as Rust does
+        let val = match (v.is_nan(), v.is_infinite(), v.is_sign_positive()) {
+            (true, _, _) => "NaN".to_string(),
+            (_, true, true) => "INF".to_string(),
+            (_, true, false) => "-INF".to_string(),
+            _ => v.to_string(),
+        };
+        Ok(SerializerData::String(val))
     }
 
-    fn serialize_char(self, v: char) -> Result<_SerializerData, Self::Error> {
+    fn serialize_char(self, v: char) -> Result<SerializerData, Self::Error> {
         self.serialize_str(&v.to_string())
     }
 
-    fn serialize_str(self, v: &str) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::CData(v.to_string()))
+    fn serialize_str(self, v: &str) -> Result<SerializerData, Self::Error> {
+        Ok(SerializerData::CData(v.to_string()))
+    }
+
+    /// Keeps the formatted string as the element's text, where the default would copy it again in
+    /// `serialize_str`. Formatting still allocates, so this saves a copy rather than avoiding one.
+    fn collect_str<T>(self, value: &T) -> Result<SerializerData, Self::Error>
+        where
+            T: ?Sized + std::fmt::Display,
+    {
+        Ok(SerializerData::CData(value.to_string()))
     }
 
-    fn serialize_bytes(self, v: &[u8]) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::String(hex::encode(v)))
+    /// Bytes are written as lowercase hex, as XML text can't hold arbitrary binary data
+    fn serialize_bytes(self, v: &[u8]) -> Result<SerializerData, Self::Error> {
+        Ok(SerializerData::String(hex::encode(v)))
     }
 
-    fn serialize_none(self) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::String("".to_string()))
+    fn serialize_none(self) -> Result<SerializerData, Self::Error> {
+        Ok(SerializerData::Absent)
     }
 
-    fn serialize_some<T>(self, value: &T) -> Result<_SerializerData, Self::Error>
+    fn serialize_some<T>(self, value: &T) -> Result<SerializerData, Self::Error>
         where
             T: ?Sized + Serialize,
     {
         value.serialize(self)
     }
 
-    fn serialize_unit(self) -> Result<_SerializerData, Self::Error> {
-        self.serialize_none()
+    fn serialize_unit(self) -> Result<SerializerData, Self::Error> {
+        // No contents at all, rather than empty text, so it's written as a self closing element
+        Ok(SerializerData::Struct {
+            attrs: vec![],
+            contents: vec![],
+        })
     }
 
-    fn serialize_unit_struct(self, _name: &'static str) -> Result<_SerializerData, Self::Error> {
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<SerializerData, Self::Error> {
         self.serialize_unit()
     }
 
@@ -431,19 +1057,19 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         _name: &'static str,
         _variant_index: u32,
         variant: &'static str,
-    ) -> Result<_SerializerData, Self::Error> {
-        self.serialize_str(variant)
+    ) -> Result<SerializerData, Self::Error> {
+        Ok(SerializerData::UnitVariant(variant))
     }
 
     fn serialize_newtype_struct<T>(
         self,
-        _name: &'static str,
+        name: &'static str,
         value: &T,
-    ) -> Result<_SerializerData, Self::Error>
+    ) -> Result<SerializerData, Self::Error>
         where
             T: ?Sized + Serialize,
     {
-        value.serialize(self)
+        Ok(SerializerData::Newtype(name, Box::new(value.serialize(self)?)))
     }
 
     fn serialize_newtype_variant<T>(
@@ -452,12 +1078,12 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         _variant_index: u32,
         variant: &'static str,
         value: &T,
-    ) -> Result<_SerializerData, Self::Error>
+    ) -> Result<SerializerData, Self::Error>
         where
             T: ?Sized + Serialize,
     {
         let value = value.serialize(&mut *self)?;
-        Ok(_SerializerData::Struct {
+        Ok(SerializerData::Struct {
             attrs: vec![],
             contents: vec![(variant.into(), value)],
         })
@@ -495,6 +1121,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
         Ok(MapSerializer {
             parent: self,
+            attrs: vec![],
             keys: vec![],
             cur_key: String::new(),
         })
@@ -526,15 +1153,20 @@ impl<'a> ser::Serializer for &'a mut Serializer {
             tag: variant.to_string(),
         })
     }
+
+    /// XML is a text format, so types with a compact binary form (like UUIDs) use their string one
+    fn is_human_readable(&self) -> bool {
+        true
+    }
 }
 
 pub struct SeqSerializer<'a> {
     parent: &'a mut Serializer,
-    output: Vec<_SerializerData>,
+    output: Vec<SerializerData>,
 }
 
 impl<'a> ser::SerializeSeq for SeqSerializer<'a> {
-    type Ok = _SerializerData;
+    type Ok = SerializerData;
     type Error = crate::Error;
 
     fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
@@ -546,13 +1178,13 @@ impl<'a> ser::SerializeSeq for SeqSerializer<'a> {
         Ok(())
     }
 
-    fn end(self) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::Seq(self.output))
+    fn end(self) -> Result<SerializerData, Self::Error> {
+        Ok(SerializerData::Seq(self.output))
     }
 }
 
 impl<'a> ser::SerializeTuple for SeqSerializer<'a> {
-    type Ok = _SerializerData;
+    type Ok = SerializerData;
     type Error = crate::Error;
 
     fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
@@ -564,13 +1196,13 @@ impl<'a> ser::SerializeTuple for SeqSerializer<'a> {
         Ok(())
     }
 
-    fn end(self) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::Seq(self.output))
+    fn end(self) -> Result<SerializerData, Self::Error> {
+        Ok(SerializerData::Seq(self.output))
     }
 }
 
 impl<'a> ser::SerializeTupleStruct for SeqSerializer<'a> {
-    type Ok = _SerializerData;
+    type Ok = SerializerData;
     type Error = crate::Error;
 
     fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
@@ -582,13 +1214,13 @@ impl<'a> ser::SerializeTupleStruct for SeqSerializer<'a> {
         Ok(())
     }
 
-    fn end(self) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::Seq(self.output))
+    fn end(self) -> Result<SerializerData, Self::Error> {
+        Ok(SerializerData::Seq(self.output))
     }
 }
 
 impl<'a> ser::SerializeTupleVariant for SeqSerializer<'a> {
-    type Ok = _SerializerData;
+    type Ok = SerializerData;
     type Error = crate::Error;
 
     fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
@@ -600,27 +1232,43 @@ impl<'a> ser::SerializeTupleVariant for SeqSerializer<'a> {
         Ok(())
     }
 
-    fn end(self) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::Seq(self.output))
+    fn end(self) -> Result<SerializerData, Self::Error> {
+        Ok(SerializerData::Seq(self.output))
     }
 }
 
+/// Serializes maps as elements, with each key naming a child element.
+///
+/// Keys must serialize as strings that are valid XML names (or one of the special `$` names), so
+/// maps such as `HashMap<String, T>` work, but `HashMap<u32, T>` doesn't.
 pub struct MapSerializer<'a> {
     parent: &'a mut Serializer,
-    keys: Vec<(Cow<'static, str>, _SerializerData)>,
+    attrs: Vec<(Cow<'static, str>, String)>,
+    keys: Vec<(Cow<'static, str>, SerializerData)>,
     cur_key: String,
 }
 
 impl<'a> ser::SerializeMap for MapSerializer<'a> {
-    type Ok = _SerializerData;
+    type Ok = SerializerData;
     type Error = crate::Error;
 
     fn serialize_key<T>(&mut self, key: &T) -> Result<(), Self::Error>
         where
             T: ?Sized + Serialize,
     {
-        let val = key.serialize(&mut *self.parent)?;
-        self.cur_key = val.as_str();
+        let key = key.serialize(&mut *self.parent)?;
+        let key = match key.unwrap_newtypes() {
+            SerializerData::String(s) | SerializerData::CData(s) => s.clone(),
+            SerializerData::UnitVariant(v) => v.to_string(),
+            // Only keys written as a single string can be element names
+            _ => return Err(crate::Error::InvalidElementName(key.as_str())),
+        };
+        // Map keys can be anything, so check they make usable element names up front; names
+        // starting with `$` keep their special meanings, as with struct fields
+        if !key.starts_with('$') {
+            validate_xml_name(&key)?;
+        }
+        self.cur_key = key;
         Ok(())
     }
 
@@ -629,26 +1277,36 @@ impl<'a> ser::SerializeMap for MapSerializer<'a> {
             T: ?Sized + Serialize,
     {
         let val = value.serialize(&mut *self.parent)?;
-        self.keys.push((self.cur_key.clone().into(), val));
+        let key = std::mem::take(&mut self.cur_key);
+        // Struct fields passed through a map by #[serde(flatten)] keep their special meanings
+        if key == "$attr_map" {
+            self.attrs.extend(val.into_attrs()?);
+        } else if let Some(attr) = key.strip_prefix("$attr:") {
+            if !matches!(val.unwrap_newtypes(), SerializerData::Absent) {
+                self.attrs.push((attr.to_string().into(), val.as_str()));
+            }
+        } else {
+            self.keys.push((key.into(), val));
+        }
         Ok(())
     }
 
-    fn end(self) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::Struct {
-            attrs: vec![],
-            contents: self.keys.into_iter().map(|(k,v)| (Cow::from(k), v)).collect(),
+    fn end(self) -> Result<SerializerData, Self::Error> {
+        Ok(SerializerData::Struct {
+            attrs: self.attrs,
+            contents: self.keys,
         })
     }
 }
 
 pub struct StructSerializer<'a> {
     parent: &'a mut Serializer,
-    attrs: Vec<(&'static str, String)>,
-    keys: Vec<(&'static str, _SerializerData)>,
+    attrs: Vec<(Cow<'static, str>, String)>,
+    keys: Vec<(&'static str, SerializerData)>,
 }
 
 impl<'a> ser::SerializeStruct for StructSerializer<'a> {
-    type Ok = _SerializerData;
+    type Ok = SerializerData;
     type Error = crate::Error;
 
     fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
@@ -656,17 +1314,21 @@ impl<'a> ser::SerializeStruct for StructSerializer<'a> {
             T: ?Sized + Serialize,
     {
         let val = value.serialize(&mut *self.parent)?;
-        if key.starts_with("$attr:") {
-            self.attrs.push((&key[6..], val.as_str()));
+        if key == "$attr_map" {
+            self.attrs.extend(val.into_attrs()?);
+        } else if let Some(attr) = key.strip_prefix("$attr:") {
+            if !matches!(val.unwrap_newtypes(), SerializerData::Absent) {
+                self.attrs.push((attr.into(), val.as_str()));
+            }
         } else {
             self.keys.push((key, val));
         }
         Ok(())
     }
 
-    fn end(self) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::Struct {
-            attrs: self.attrs.into_iter().map(|(k,v)| (k.into(), v)).collect(),
+    fn end(self) -> Result<SerializerData, Self::Error> {
+        Ok(SerializerData::Struct {
+            attrs: self.attrs,
             contents: self.keys.into_iter().map(|(k,v)| (k.into(), v)).collect(),
         })
     }
@@ -675,12 +1337,12 @@ impl<'a> ser::SerializeStruct for StructSerializer<'a> {
 pub struct StructVariantSerializer<'a> {
     parent: &'a mut Serializer,
     attrs: Vec<(Cow<'static, str>, String)>,
-    keys: Vec<(Cow<'static, str>, _SerializerData)>,
+    keys: Vec<(Cow<'static, str>, SerializerData)>,
     tag: String,
 }
 
 impl<'a> ser::SerializeStructVariant for StructVariantSerializer<'a> {
-    type Ok = _SerializerData;
+    type Ok = SerializerData;
     type Error = crate::Error;
 
     fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
@@ -688,21 +1350,1865 @@ impl<'a> ser::SerializeStructVariant for StructVariantSerializer<'a> {
             T: ?Sized + Serialize,
     {
         let val = value.serialize(&mut *self.parent)?;
-        if key.starts_with("$attr:") {
-            self.attrs.push((key[6..].into(), val.as_str()));
+        if key == "$attr_map" {
+            self.attrs.extend(val.into_attrs()?);
+        } else if let Some(attr) = key.strip_prefix("$attr:") {
+            if !matches!(val.unwrap_newtypes(), SerializerData::Absent) {
+                self.attrs.push((attr.into(), val.as_str()));
+            }
         } else {
             self.keys.push((key.into(), val));
         }
         Ok(())
     }
 
-    fn end(self) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::Struct {
+    fn end(self) -> Result<SerializerData, Self::Error> {
+        Ok(SerializerData::Struct {
             attrs: vec![],
-            contents: vec![(self.tag.into(), _SerializerData::Struct {
+            contents: vec![(self.tag.into(), SerializerData::Struct {
                 attrs: self.attrs,
                 contents: self.keys,
             })],
         })
     }
 }
+
+/// Discards everything written to it, for the first pass of `to_writer_custom`
+struct NullWriter;
+
+impl EventWriter for NullWriter {
+    fn write(&mut self, _event: xml::writer::XmlEvent<'_>) -> xml::writer::Result<()> {
+        Ok(())
+    }
+}
+
+/// What the first pass of `to_writer_custom` finds out about a value, so the second can write it
+/// out as `format_data` would
+#[derive(Default)]
+struct StreamPlan {
+    /// Whether this is the first pass, where nothing is really written
+    first_pass: bool,
+    /// Number of structs started so far, to match them up between the passes
+    structs: usize,
+    /// Structs with attributes after their other fields, by number. The start of their element
+    /// can't be written until the last attribute is known, so this gives how many fields to hold
+    /// back, and how many structs are started within those fields.
+    held: std::collections::BTreeMap<usize, (usize, usize)>,
+}
+
+/// Where `StreamSerializer` writes a value, matching what `format_data` would write for its data
+#[derive(Clone, Copy)]
+enum StreamTarget {
+    /// Into the contents of the current element
+    Contents,
+    /// As elements with this tag, one for each item if it's a sequence
+    Elements(&'static str),
+    /// As a single element with this tag
+    Element(&'static str),
+}
+
+impl StreamTarget {
+    fn element(self) -> Option<&'static str> {
+        match self {
+            StreamTarget::Contents => None,
+            StreamTarget::Elements(tag) | StreamTarget::Element(tag) => Some(tag),
+        }
+    }
+}
+
+/// Writes out structs and sequences as they're serialized, rather than building `SerializerData`
+/// for the whole document first; anything else is serialized in full and written by `format_data`.
+struct StreamSerializer<'a> {
+    writer: &'a mut dyn EventWriter,
+    state: &'a mut SerializerState,
+    buffer: &'a mut Serializer,
+    plan: &'a mut StreamPlan,
+    target: StreamTarget,
+}
+
+fn write_streamed(writer: &mut dyn EventWriter, state: &mut SerializerState, target: StreamTarget, d: SerializerData) -> Result<(), crate::Error> {
+    match target {
+        StreamTarget::Contents => format_data(writer, &d, state),
+        StreamTarget::Elements(tag) => format_element(writer, &Tag::from_static(tag), &d, state),
+        StreamTarget::Element(tag) => format_element(writer, &Tag::from_static(tag), &SerializerData::Seq(vec![d]), state),
+    }
+}
+
+macro_rules! stream_buffered {
+    ($($method:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(fn $method(self, $($arg: $ty),*) -> Result<(), crate::Error> {
+            let d = ser::Serializer::$method(&mut *self.buffer, $($arg),*)?;
+            write_streamed(self.writer, self.state, self.target, d)
+        })*
+    }
+}
+
+impl<'a> ser::Serializer for StreamSerializer<'a> {
+    type Ok = ();
+    type Error = crate::Error;
+    type SerializeSeq = StreamSeq<'a>;
+    type SerializeTuple = StreamSeq<'a>;
+    type SerializeTupleStruct = StreamSeq<'a>;
+    type SerializeTupleVariant = StreamSeq<'a>;
+    type SerializeMap = StreamBuffered<'a, MapSerializer<'a>>;
+    type SerializeStruct = StreamStruct<'a>;
+    type SerializeStructVariant = StreamBuffered<'a, StructVariantSerializer<'a>>;
+
+    stream_buffered! {
+        serialize_bool(v: bool);
+        serialize_i8(v: i8);
+        serialize_i16(v: i16);
+        serialize_i32(v: i32);
+        serialize_i64(v: i64);
+        serialize_u8(v: u8);
+        serialize_u16(v: u16);
+        serialize_u32(v: u32);
+        serialize_u64(v: u64);
+        serialize_f32(v: f32);
+        serialize_f64(v: f64);
+        serialize_char(v: char);
+        serialize_str(v: &str);
+        serialize_bytes(v: &[u8]);
+        serialize_none();
+        serialize_unit();
+        serialize_unit_struct(name: &'static str);
+        serialize_unit_variant(name: &'static str, variant_index: u32, variant: &'static str);
+    }
+
+    fn collect_str<T>(self, value: &T) -> Result<(), Self::Error>
+        where
+            T: ?Sized + std::fmt::Display,
+    {
+        let d = ser::Serializer::collect_str(&mut *self.buffer, value)?;
+        write_streamed(self.writer, self.state, self.target, d)
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<(), Self::Error>
+        where
+            T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<(), Self::Error>
+        where
+            T: ?Sized + Serialize,
+    {
+        if !self.state.newtype_struct_as_wrapper {
+            return value.serialize(self);
+        }
+        let element = self.target.element();
+        if let Some(tag) = element {
+            self.state.record_tag(&Tag::from_static(tag));
+            start_element(self.writer, &Tag::from_static(tag), &[], self.state)?;
+        }
+        self.state.record_tag(&Tag::from_static(name));
+        value.serialize(StreamSerializer {
+            writer: &mut *self.writer,
+            state: &mut *self.state,
+            buffer: &mut *self.buffer,
+            plan: &mut *self.plan,
+            target: StreamTarget::Elements(name),
+        })?;
+        if element.is_some() {
+            self.writer.write(xml::writer::XmlEvent::end_element().into())?;
+        }
+        Ok(())
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error>
+        where
+            T: ?Sized + Serialize,
+    {
+        let d = ser::Serializer::serialize_newtype_variant(&mut *self.buffer, name, variant_index, variant, value)?;
+        write_streamed(self.writer, self.state, self.target, d)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        let (element, item_target) = match self.target {
+            StreamTarget::Contents => (None, StreamTarget::Contents),
+            StreamTarget::Elements(tag) => {
+                self.state.record_tag(&Tag::from_static(tag));
+                (None, StreamTarget::Element(tag))
+            }
+            // A sequence within a single element is written as the contents of that element
+            StreamTarget::Element(tag) => (Some(tag), StreamTarget::Contents),
+        };
+        if let Some(tag) = element {
+            start_element(self.writer, &Tag::from_static(tag), &[], self.state)?;
+        }
+        Ok(StreamSeq {
+            writer: self.writer,
+            state: self.state,
+            buffer: self.buffer,
+            plan: self.plan,
+            element,
+            item_target,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Ok(StreamBuffered {
+            inner: ser::Serializer::serialize_map(self.buffer, len)?,
+            writer: self.writer,
+            state: self.state,
+            target: self.target,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct, Self::Error> {
+        let element = self.target.element();
+        if let Some(tag) = element {
+            self.state.record_tag(&Tag::from_static(tag));
+        }
+        let index = self.plan.structs;
+        self.plan.structs += 1;
+        // Structs within held back fields are serialized in full, so aren't counted on the second pass
+        let hold = match self.plan.held.get(&index) {
+            Some(&(hold, structs)) if !self.plan.first_pass => {
+                self.plan.structs += structs;
+                hold
+            }
+            _ => 0
+        };
+        Ok(StreamStruct {
+            writer: self.writer,
+            state: self.state,
+            buffer: self.buffer,
+            plan: self.plan,
+            element,
+            index,
+            fields: 0,
+            hold,
+            held: vec![],
+            started: false,
+            attrs: vec![],
+        })
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Ok(StreamBuffered {
+            inner: ser::Serializer::serialize_struct_variant(self.buffer, name, variant_index, variant, len)?,
+            writer: self.writer,
+            state: self.state,
+            target: self.target,
+        })
+    }
+}
+
+struct StreamSeq<'a> {
+    writer: &'a mut dyn EventWriter,
+    state: &'a mut SerializerState,
+    buffer: &'a mut Serializer,
+    plan: &'a mut StreamPlan,
+    /// The element the items are written within, if they don't each get their own
+    element: Option<&'static str>,
+    item_target: StreamTarget,
+}
+
+impl<'a> StreamSeq<'a> {
+    fn write_item<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), crate::Error> {
+        value.serialize(StreamSerializer {
+            writer: &mut *self.writer,
+            state: &mut *self.state,
+            buffer: &mut *self.buffer,
+            plan: &mut *self.plan,
+            target: self.item_target,
+        })
+    }
+
+    fn finish(self) -> Result<(), crate::Error> {
+        if self.element.is_some() {
+            self.writer.write(xml::writer::XmlEvent::end_element().into())?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeSeq for StreamSeq<'a> {
+    type Ok = ();
+    type Error = crate::Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
+        where
+            T: ?Sized + Serialize,
+    {
+        self.write_item(value)
+    }
+
+    fn end(self) -> Result<(), Self::Error> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTuple for StreamSeq<'a> {
+    type Ok = ();
+    type Error = crate::Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
+        where
+            T: ?Sized + Serialize,
+    {
+        self.write_item(value)
+    }
+
+    fn end(self) -> Result<(), Self::Error> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for StreamSeq<'a> {
+    type Ok = ();
+    type Error = crate::Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
+        where
+            T: ?Sized + Serialize,
+    {
+        self.write_item(value)
+    }
+
+    fn end(self) -> Result<(), Self::Error> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for StreamSeq<'a> {
+    type Ok = ();
+    type Error = crate::Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
+        where
+            T: ?Sized + Serialize,
+    {
+        self.write_item(value)
+    }
+
+    fn end(self) -> Result<(), Self::Error> {
+        self.finish()
+    }
+}
+
+struct StreamStruct<'a> {
+    writer: &'a mut dyn EventWriter,
+    state: &'a mut SerializerState,
+    buffer: &'a mut Serializer,
+    plan: &'a mut StreamPlan,
+    /// The element the fields are written within, if any
+    element: Option<&'static str>,
+    /// Number of this struct, as counted in `StreamPlan`
+    index: usize,
+    /// Number of fields seen so far
+    fields: usize,
+    /// Number of fields to hold back until the start of the element can be written
+    hold: usize,
+    held: Vec<(Cow<'static, str>, SerializerData)>,
+    started: bool,
+    attrs: Vec<(Cow<'static, str>, String)>,
+}
+
+impl<'a> StreamStruct<'a> {
+    /// Write the start of the element, followed by any fields held back for it
+    fn start(&mut self) -> Result<(), crate::Error> {
+        if let Some(tag) = self.element {
+            if !std::mem::replace(&mut self.started, true) {
+                start_element(self.writer, &Tag::from_static(tag), &self.attrs, self.state)?;
+            }
+        }
+        for field in std::mem::take(&mut self.held) {
+            format_field(self.writer, &field, self.state)?;
+        }
+        Ok(())
+    }
+
+    fn write_field<T: ?Sized + Serialize>(&mut self, value: &T, target: StreamTarget) -> Result<(), crate::Error> {
+        value.serialize(StreamSerializer {
+            writer: &mut *self.writer,
+            state: &mut *self.state,
+            buffer: &mut *self.buffer,
+            plan: &mut *self.plan,
+            target,
+        })
+    }
+}
+
+impl<'a> ser::SerializeStruct for StreamStruct<'a> {
+    type Ok = ();
+    type Error = crate::Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
+        where
+            T: ?Sized + Serialize,
+    {
+        let field = self.fields;
+        self.fields += 1;
+        if key == "$attr_map" || key.starts_with("$attr:") {
+            if self.started {
+                if !self.plan.first_pass {
+                    return Err(crate::Error::Message("value serialized differently on the second pass".into()));
+                }
+                // Hold back everything up to here on the second pass
+                let structs = self.plan.structs - self.index - 1;
+                self.plan.held.insert(self.index, (field + 1, structs));
+            }
+            let val = value.serialize(&mut *self.buffer)?;
+            if key == "$attr_map" {
+                self.attrs.extend(val.into_attrs()?);
+            } else if !matches!(val.unwrap_newtypes(), SerializerData::Absent) {
+                self.attrs.push((key["$attr:".len()..].into(), val.as_str()));
+            }
+        } else if field < self.hold {
+            self.held.push((key.into(), value.serialize(&mut *self.buffer)?));
+        } else {
+            self.start()?;
+            if key == "$valueRaw" {
+                let old_val = std::mem::replace(&mut self.state.raw_output, true);
+                self.write_field(value, StreamTarget::Contents)?;
+                self.state.raw_output = old_val;
+            } else if key.starts_with("$value") {
+                self.write_field(value, StreamTarget::Contents)?;
+            } else if key == "$list" {
+                let d = value.serialize(&mut *self.buffer)?;
+                format_field(self.writer, &(key.into(), d), self.state)?;
+            } else {
+                validate_xml_name(key)?;
+                self.write_field(value, StreamTarget::Elements(key))?;
+            }
+        }
+        if field + 1 == self.hold {
+            self.start()?;
+        }
+        Ok(())
+    }
+
+    fn end(mut self) -> Result<(), Self::Error> {
+        self.start()?;
+        if self.element.is_some() {
+            self.writer.write(xml::writer::XmlEvent::end_element().into())?;
+        }
+        Ok(())
+    }
+}
+
+/// A value that can't be streamed, which is written out once it's been serialized in full
+struct StreamBuffered<'a, S> {
+    inner: S,
+    writer: &'a mut dyn EventWriter,
+    state: &'a mut SerializerState,
+    target: StreamTarget,
+}
+
+impl<'a> ser::SerializeMap for StreamBuffered<'a, MapSerializer<'a>> {
+    type Ok = ();
+    type Error = crate::Error;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), Self::Error>
+        where
+            T: ?Sized + Serialize,
+    {
+        self.inner.serialize_key(key)
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Self::Error>
+        where
+            T: ?Sized + Serialize,
+    {
+        self.inner.serialize_value(value)
+    }
+
+    fn end(self) -> Result<(), Self::Error> {
+        let d = self.inner.end()?;
+        write_streamed(self.writer, self.state, self.target, d)
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for StreamBuffered<'a, StructVariantSerializer<'a>> {
+    type Ok = ();
+    type Error = crate::Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
+        where
+            T: ?Sized + Serialize,
+    {
+        self.inner.serialize_field(key, value)
+    }
+
+    fn end(self) -> Result<(), Self::Error> {
+        let d = self.inner.end()?;
+        write_streamed(self.writer, self.state, self.target, d)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn serialize_struct_variant_with_attrs() {
+        #[derive(Debug, Serialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Bar,
+        }
+
+        #[derive(Debug, Serialize)]
+        enum Bar {
+            #[serde(rename = "{urn:foo}foo:baz")]
+            Baz {
+                #[serde(rename = "$attr:id")]
+                id: String,
+                #[serde(rename = "{urn:foo}foo:qux")]
+                qux: String,
+            },
+        }
+
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .build();
+        assert_eq!(
+            crate::to_string_custom(&Foo {
+                bar: Bar::Baz {
+                    id: "1".to_string(),
+                    qux: "2".to_string(),
+                }
+            }, options).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<foo:bar xmlns:foo="urn:foo">
+  <foo:baz id="1">
+    <foo:qux>2</foo:qux>
+  </foo:baz>
+</foo:bar>"#
+        );
+    }
+
+    #[test]
+    fn serialize_attr_map() {
+        #[derive(Debug, Serialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Bar,
+        }
+
+        #[derive(Debug, Serialize)]
+        struct Bar {
+            #[serde(rename = "$attr:id")]
+            id: String,
+            #[serde(rename = "$attr_map")]
+            extra: std::collections::BTreeMap<String, String>,
+        }
+
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .build();
+        assert_eq!(
+            crate::to_string_custom(&Foo {
+                bar: Bar {
+                    id: "1".to_string(),
+                    extra: vec![
+                        ("data-a".to_string(), "2".to_string()),
+                        ("data-b".to_string(), "3".to_string()),
+                    ].into_iter().collect(),
+                }
+            }, options).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<foo:bar xmlns:foo="urn:foo" id="1" data-a="2" data-b="3"/>"#
+        );
+    }
+
+    #[test]
+    fn serialize_hoisted_namespaces() {
+        #[derive(Debug, Serialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Bar,
+        }
+
+        #[derive(Debug, Serialize)]
+        struct Bar {
+            #[serde(rename = "{urn:baz}baz:baz")]
+            baz: Vec<String>,
+            #[serde(rename = "{urn:qux}qux")]
+            qux: String,
+        }
+
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .hoist_namespaces(true)
+            .build();
+        assert_eq!(
+            crate::to_string_custom(&Foo {
+                bar: Bar {
+                    baz: vec!["1".to_string(), "2".to_string()],
+                    qux: "3".to_string(),
+                }
+            }, options).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<foo:bar xmlns:baz="urn:baz" xmlns:foo="urn:foo">
+  <baz:baz>1</baz:baz>
+  <baz:baz>2</baz:baz>
+  <qux xmlns="urn:qux">3</qux>
+</foo:bar>"#
+        );
+    }
+
+    #[test]
+    fn round_trip_hoisted_default_namespace() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Doc {
+            foo: Foo,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}a")]
+            a: String,
+            b: String,
+        }
+
+        let value = Doc {
+            foo: Foo {
+                a: "1".to_string(),
+                b: "2".to_string(),
+            }
+        };
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false)
+            .hoist_namespaces(true)
+            .build();
+        let xml = crate::to_string_custom(&value, options).unwrap();
+        assert_xml_eq!(xml, r#"<foo><a xmlns="urn:foo">1</a><b>2</b></foo>"#);
+        assert_eq!(crate::from_str::<Doc>(&xml).unwrap(), value);
+    }
+
+    #[test]
+    fn serialize_schema_location_on_root() {
+        #[derive(Debug, Serialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Bar,
+        }
+
+        #[derive(Debug, Serialize)]
+        struct Bar {
+            #[serde(rename = "{urn:ietf:params:xml:ns:baz-1.0}baz:baz")]
+            baz: String,
+        }
+
+        assert_eq!(
+            crate::to_string(&Foo {
+                bar: Bar {
+                    baz: "1".to_string(),
+                }
+            }).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<foo:bar xmlns:foo="urn:foo" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="urn:foo foo.xsd urn:ietf:params:xml:ns:baz-1.0 baz-1.0.xsd">
+  <baz:baz xmlns:baz="urn:ietf:params:xml:ns:baz-1.0">1</baz:baz>
+</foo:bar>"#
+        );
+    }
+
+    #[test]
+    fn to_events_closes_elements() {
+        #[derive(Serialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Vec<String>,
+        }
+
+        let events = crate::to_events(&Foo {
+            bar: vec!["a".to_string(), "b".to_string()],
+        }).unwrap();
+        let end_names = events.iter().filter_map(|e| match e {
+            xml::reader::XmlEvent::EndElement { name } => Some(name.to_string()),
+            _ => None
+        }).collect::<Vec<_>>();
+        assert_eq!(end_names, vec!["{urn:foo}foo:bar", "{urn:foo}foo:bar"]);
+    }
+
+    #[test]
+    fn serialize_invalid_element_names() {
+        fn serialize(name: &str) -> crate::Result<String> {
+            let value = [(name.to_string(), "a".to_string())].into_iter().collect::<std::collections::HashMap<_, _>>();
+            crate::to_string(&value)
+        }
+
+        assert!(serialize("foo").is_ok());
+        assert!(serialize("{urn:foo}foo:bar-baz.1").is_ok());
+        assert_eq!(serialize("1foo"), Err(crate::Error::InvalidElementName("1foo".to_string())));
+        assert_eq!(serialize("foo&bar"), Err(crate::Error::InvalidElementName("foo&bar".to_string())));
+        assert_eq!(serialize("foo bar"), Err(crate::Error::InvalidElementName("foo bar".to_string())));
+        assert_eq!(serialize("1:foo"), Err(crate::Error::InvalidElementName("1:foo".to_string())));
+        assert_eq!(serialize(""), Err(crate::Error::InvalidElementName("".to_string())));
+        assert_eq!(serialize("{urn:foo}"), Err(crate::Error::InvalidElementName("{urn:foo}".to_string())));
+    }
+
+    #[test]
+    fn serialize_none() {
+        #[derive(Serialize)]
+        struct Foo {
+            bar: Bar,
+        }
+
+        #[derive(Serialize)]
+        struct Bar {
+            #[serde(rename = "$attr:id")]
+            id: Option<String>,
+            #[serde(rename = "{urn:foo}foo:a")]
+            a: Option<String>,
+            b: Option<String>,
+        }
+
+        let value = Foo {
+            bar: Bar {
+                id: None,
+                a: None,
+                b: Some("x".to_string()),
+            }
+        };
+        assert_eq!(
+            crate::to_string(&value).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<bar xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+  <b>x</b>
+</bar>"#
+        );
+        let options = crate::Options::builder()
+            .absent_as_empty_element(true)
+            .build();
+        assert_eq!(
+            crate::to_string_custom(&value, options).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<bar xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="urn:foo foo.xsd">
+  <foo:a xmlns:foo="urn:foo"/>
+  <b>x</b>
+</bar>"#
+        );
+    }
+
+    #[test]
+    fn serialize_fragment() {
+        #[derive(Serialize)]
+        struct Message {
+            message: Body,
+        }
+
+        #[derive(Serialize)]
+        struct Body {
+            #[serde(rename = "$attr:to")]
+            to: String,
+            body: String,
+        }
+
+        assert_eq!(
+            crate::to_fragment_string(&Message {
+                message: Body {
+                    to: "a@example.com".to_string(),
+                    body: "hi".to_string(),
+                }
+            }).unwrap(),
+            r#"<message xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" to="a@example.com">
+  <body>hi</body>
+</message>"#
+        );
+    }
+
+    #[test]
+    fn serializer_data_to_string() {
+        let data = super::SerializerData::element("{urn:foo}foo:bar", vec![], vec![
+            ("{urn:foo}foo:baz".to_string(), super::SerializerData::text("a & b")),
+        ]);
+        assert_eq!(
+            String::try_from(data).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<foo:bar xmlns:foo="urn:foo" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="urn:foo foo.xsd">
+  <foo:baz>a &amp; b</foo:baz>
+</foo:bar>"#
+        );
+    }
+
+    #[test]
+    fn serialize_unit() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Bar {
+            a: Unit,
+            b: (),
+            c: Baz,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Unit;
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        enum Baz {
+            A,
+        }
+
+        let value = Foo {
+            foo: Bar {
+                a: Unit,
+                b: (),
+                c: Baz::A,
+            }
+        };
+        let xml = crate::to_string(&value).unwrap();
+        assert_eq!(
+            xml,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<foo xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+  <a/>
+  <b/>
+  <c>A</c>
+</foo>"#
+        );
+        assert_eq!(crate::from_str::<Foo>(&xml).unwrap(), value);
+    }
+
+    #[test]
+    fn serialize_unit_variant_as_element() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Bar {
+            status: Status,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        enum Status {
+            #[serde(rename = "{urn:foo}foo:ok")]
+            Ok,
+        }
+
+        let value = Foo {
+            foo: Bar {
+                status: Status::Ok,
+            }
+        };
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .unit_variant_as_element(true)
+            .build();
+        let xml = crate::to_string_custom(&value, options).unwrap();
+        assert_eq!(
+            xml,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<foo>
+  <status>
+    <foo:ok xmlns:foo="urn:foo"/>
+  </status>
+</foo>"#
+        );
+        assert_eq!(crate::from_str::<Foo>(&xml).unwrap(), value);
+    }
+
+    #[test]
+    fn serialize_map_with_invalid_keys() {
+        #[derive(Serialize)]
+        struct Foo {
+            foo: std::collections::HashMap<i32, String>,
+        }
+
+        let value = Foo {
+            foo: vec![(42, "a".to_string())].into_iter().collect(),
+        };
+        assert_eq!(crate::to_string(&value), Err(crate::Error::InvalidElementName("42".to_string())));
+
+        #[derive(Serialize)]
+        struct Bar {
+            bar: std::collections::BTreeMap<(String, String), String>,
+        }
+
+        let value = Bar {
+            bar: vec![(("a".to_string(), "b".to_string()), "c".to_string())].into_iter().collect(),
+        };
+        assert_eq!(crate::to_string(&value), Err(crate::Error::InvalidElementName("a,b".to_string())));
+
+        #[derive(PartialEq, Eq, PartialOrd, Ord, Serialize)]
+        enum Key {
+            #[serde(rename = "a")]
+            A,
+        }
+
+        #[derive(Serialize)]
+        struct Baz {
+            baz: std::collections::BTreeMap<Key, String>,
+        }
+
+        let value = Baz {
+            baz: vec![(Key::A, "1".to_string())].into_iter().collect(),
+        };
+        assert!(crate::to_string(&value).unwrap().contains("<a>1</a>"));
+    }
+
+    #[test]
+    fn xsi_declared_once() {
+        #[derive(Serialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:foo")]
+            foo: Bar,
+        }
+
+        #[derive(Serialize)]
+        struct Bar {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Vec<String>,
+        }
+
+        /// Counts the elements written with an `xsi` namespace declaration
+        #[derive(Default)]
+        struct XsiCounter(usize);
+
+        impl crate::EventWriter for XsiCounter {
+            fn write(&mut self, event: xml::writer::XmlEvent<'_>) -> xml::writer::Result<()> {
+                if let xml::writer::XmlEvent::StartElement { namespace, .. } = event {
+                    if namespace.get("xsi").is_some() {
+                        self.0 += 1;
+                    }
+                }
+                Ok(())
+            }
+        }
+
+        let value = Foo {
+            foo: Bar {
+                bar: vec!["a".to_string(), "b".to_string()],
+            }
+        };
+        let options = crate::Options::default();
+        let data = serde::Serialize::serialize(&value, &mut super::Serializer).unwrap();
+        let mut counter = XsiCounter::default();
+        crate::format_data(&mut counter, &data, &mut crate::SerializerState::new(&data, &options)).unwrap();
+        assert_eq!(counter.0, 1);
+    }
+
+    #[test]
+    fn serialize_list() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            sizes: Sizes,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Sizes {
+            #[serde(rename = "$attr:unit")]
+            unit: String,
+            #[serde(rename = "$list")]
+            sizes: Vec<u32>,
+        }
+
+        let value = Foo {
+            sizes: Sizes {
+                unit: "cm".to_string(),
+                sizes: vec![1, 2, 3, 4],
+            }
+        };
+        let xml = crate::to_string(&value).unwrap();
+        assert_eq!(
+            xml,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<sizes xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" unit="cm">1 2 3 4</sizes>"#
+        );
+        assert_eq!(crate::from_str::<Foo>(&xml).unwrap(), value);
+    }
+
+    #[test]
+    fn serialize_crlf() {
+        #[derive(Serialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Serialize)]
+        struct Bar {
+            a: String,
+            b: String,
+        }
+
+        let value = Foo {
+            foo: Bar {
+                a: "1".to_string(),
+                b: "2".to_string(),
+            }
+        };
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .line_ending(crate::LineEnding::CrLf)
+            .build();
+        let xml = crate::to_string_custom(&value, options.clone()).unwrap();
+        assert_eq!(
+            xml,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\r\n<foo>\r\n  <a>1</a>\r\n  <b>2</b>\r\n</foo>"
+        );
+        let mut output = vec![];
+        crate::to_writer_custom(&mut output, &value, options).unwrap();
+        assert_eq!(output, xml.as_bytes());
+    }
+
+    #[test]
+    fn estimate_output_size() {
+        #[derive(Serialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Serialize)]
+        struct Bar {
+            #[serde(rename = "$attr:id")]
+            id: String,
+            a: Vec<String>,
+            b: Option<String>,
+        }
+
+        let value = Foo {
+            foo: Bar {
+                id: "1".to_string(),
+                a: vec!["x".repeat(10), "y".repeat(100)],
+                b: None,
+            }
+        };
+        let data = serde::Serialize::serialize(&value, &mut super::Serializer).unwrap();
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false)
+            .build();
+        let output = super::data_to_string(&data, &options).unwrap();
+        let estimate = super::estimate_output_size(&data);
+        assert!(estimate >= output.len(), "{} < {}", estimate, output.len());
+        assert!(estimate <= output.len() * 2, "{} > 2 * {}", estimate, output.len());
+    }
+
+    #[test]
+    fn to_events_document_events() {
+        #[derive(Serialize)]
+        struct Foo {
+            foo: String,
+        }
+
+        let value = Foo {
+            foo: "a".to_string(),
+        };
+        let events = crate::to_events(&value).unwrap();
+        assert!(matches!(events.first(), Some(xml::reader::XmlEvent::StartDocument { .. })));
+        assert!(matches!(events.last(), Some(xml::reader::XmlEvent::EndDocument)));
+
+        let options = crate::Options::builder()
+            .write_document_declaration(false)
+            .include_end_document(false)
+            .build();
+        let mut events = crate::to_events_custom(&value, options.clone()).unwrap();
+        assert!(matches!(events.first(), Some(xml::reader::XmlEvent::StartElement { .. })));
+        assert!(matches!(events.last(), Some(xml::reader::XmlEvent::EndElement { .. })));
+        // Without the document events, lists of events can be joined together
+        events.extend(crate::to_events_custom(&value, options).unwrap());
+        let names = events.iter().filter_map(|e| match e {
+            xml::reader::XmlEvent::StartElement { name, .. } => Some(name.local_name.as_str()),
+            _ => None
+        }).collect::<Vec<_>>();
+        assert_eq!(names, vec!["foo", "foo"]);
+    }
+
+    #[test]
+    fn serialize_newtype_struct_as_wrapper() {
+        #[derive(Serialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Serialize)]
+        struct Bar {
+            length: Meters,
+            width: Vec<Meters>,
+        }
+
+        #[derive(Serialize)]
+        struct Meters(u32);
+
+        let value = Foo {
+            foo: Bar {
+                length: Meters(5),
+                width: vec![Meters(1), Meters(2)],
+            }
+        };
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false)
+            .indent_size(0);
+        assert_eq!(
+            crate::to_string_custom(&value, options.clone().build()).unwrap(),
+            "<foo>\n<length>5</length>\n<width>1</width>\n<width>2</width>\n</foo>"
+        );
+        assert_eq!(
+            crate::to_string_custom(&value, options.newtype_struct_as_wrapper(true).build()).unwrap(),
+            "<foo>\n<length>\n<Meters>5</Meters>\n</length>\n<width>\n<Meters>1</Meters>\n</width>\n<width>\n<Meters>2</Meters>\n</width>\n</foo>"
+        );
+    }
+
+    #[test]
+    fn to_events_round_trip() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:foo")]
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Bar {
+            #[serde(rename = "$attr:id")]
+            id: String,
+            #[serde(rename = "{urn:foo}foo:a")]
+            a: Vec<String>,
+            #[serde(rename = "{urn:foo}foo:b")]
+            b: String,
+        }
+
+        let value = Foo {
+            foo: Bar {
+                id: r#"1 < "2""#.to_string(),
+                a: vec!["x & y".to_string(), "y".to_string()],
+                b: "<z/>".to_string(),
+            }
+        };
+        let events = crate::to_events(&value).unwrap();
+        assert!(matches!(events.last(), Some(xml::reader::XmlEvent::EndDocument)));
+        let events = events.into_iter().map(Ok).collect::<Vec<_>>();
+        assert_eq!(crate::from_events::<Foo>(&events).unwrap(), value);
+    }
+
+    #[test]
+    fn to_writer_large_list() {
+        #[derive(Serialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Serialize)]
+        struct Bar {
+            item: Vec<u32>,
+        }
+
+        let value = Foo {
+            foo: Bar {
+                item: (0..10_000).collect(),
+            }
+        };
+        let mut output = vec![];
+        crate::to_writer(&mut output, &value).unwrap();
+        assert_eq!(output, crate::to_string(&value).unwrap().into_bytes());
+        assert_eq!(String::from_utf8(output).unwrap().matches("<item>").count(), 10_000);
+    }
+
+    #[test]
+    fn serialize_namespaced_variants() {
+        #[derive(Serialize)]
+        struct Message {
+            #[serde(rename = "{urn:ietf:params:xml:ns:epp-1.0}epp")]
+            message: MessageType,
+        }
+
+        #[derive(Serialize)]
+        enum MessageType {
+            #[serde(rename = "{urn:ietf:params:xml:ns:epp-1.0}hello")]
+            Hello {},
+            #[serde(rename = "{urn:ietf:params:xml:ns:epp-1.0}command")]
+            Command(Command),
+            #[serde(rename = "{urn:example}ex:extension")]
+            Extension {
+                #[serde(rename = "{urn:example}ex:name")]
+                name: String,
+            },
+        }
+
+        #[derive(Serialize)]
+        struct Command {
+            #[serde(rename = "{urn:ietf:params:xml:ns:epp-1.0}clTRID")]
+            client_transaction_id: String,
+        }
+
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false)
+            .build();
+        let serialize = |message| crate::to_string_custom(&Message { message }, options.clone()).unwrap();
+        assert_eq!(
+            serialize(MessageType::Hello {}),
+            "<epp xmlns=\"urn:ietf:params:xml:ns:epp-1.0\">\n  <hello/>\n</epp>"
+        );
+        assert_eq!(
+            serialize(MessageType::Command(Command { client_transaction_id: "a".to_string() })),
+            "<epp xmlns=\"urn:ietf:params:xml:ns:epp-1.0\">\n  <command>\n    <clTRID>a</clTRID>\n  </command>\n</epp>"
+        );
+        assert_eq!(
+            serialize(MessageType::Extension { name: "b".to_string() }),
+            "<epp xmlns=\"urn:ietf:params:xml:ns:epp-1.0\">\n  <ex:extension xmlns:ex=\"urn:example\">\n    <ex:name>b</ex:name>\n  </ex:extension>\n</epp>"
+        );
+
+        // The variant's own element is in its namespace, even when it's the only one in the document
+        let events = crate::to_events(&MessageType::Extension { name: "b".to_string() }).unwrap();
+        let names = events.iter().filter_map(|e| match e {
+            xml::reader::XmlEvent::StartElement { name, .. } => Some(name.to_string()),
+            _ => None
+        }).collect::<Vec<_>>();
+        assert_eq!(names, vec!["{urn:example}ex:extension", "{urn:example}ex:name"]);
+    }
+
+    #[test]
+    fn round_trip_xml_space() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Bar {
+            #[serde(rename = "$attr:{http://www.w3.org/XML/1998/namespace}space")]
+            space: String,
+            #[serde(rename = "$value")]
+            value: String,
+        }
+
+        let value = Foo {
+            foo: Bar {
+                space: "preserve".to_string(),
+                value: "a b".to_string(),
+            }
+        };
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false)
+            .build();
+        let xml = crate::to_string_custom(&value, options).unwrap();
+        assert_eq!(xml, r#"<foo xml:space="preserve">a b</foo>"#);
+        assert_eq!(crate::from_str::<Foo>(&xml).unwrap(), value);
+        assert_eq!(crate::from_str::<Foo>(r#"<foo xml:space="preserve">a b</foo>"#).unwrap(), value);
+    }
+
+    #[test]
+    fn round_trip_default_namespace() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Bar {
+            a: String,
+            #[serde(rename = "{urn:other}o:b")]
+            b: Baz,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Baz {
+            c: String,
+        }
+
+        let value = Foo {
+            foo: Bar {
+                a: "1".to_string(),
+                b: Baz {
+                    c: "2".to_string(),
+                },
+            }
+        };
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false)
+            .default_namespace("urn:foo")
+            .build();
+        let xml = crate::to_string_custom(&value, options).unwrap();
+        assert_eq!(
+            xml,
+            "<foo xmlns=\"urn:foo\">\n  <a>1</a>\n  <o:b xmlns:o=\"urn:other\">\n    <c>2</c>\n  </o:b>\n</foo>"
+        );
+
+        let config = crate::DeserializerConfig {
+            default_namespace: Some("urn:foo".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(crate::from_str_with_config::<Foo>(&xml, config).unwrap(), value);
+        // Without the default namespace, the elements in it aren't matched
+        assert!(crate::from_str::<Foo>(&xml).is_err());
+    }
+
+    #[test]
+    fn serialize_human_readable() {
+        // Serializes like `uuid::Uuid`, as a string or as bytes depending on the format
+        struct Id([u8; 4]);
+
+        impl serde::Serialize for Id {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                if serializer.is_human_readable() {
+                    serializer.serialize_str(&format!("{}-{}", hex::encode(&self.0[..2]), hex::encode(&self.0[2..])))
+                } else {
+                    serializer.serialize_bytes(&self.0)
+                }
+            }
+        }
+
+        #[derive(Serialize)]
+        struct Foo {
+            id: Id,
+        }
+
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false)
+            .build();
+        let xml = crate::to_string_custom(&Foo { id: Id([0x55, 0x0e, 0x84, 0x00]) }, options).unwrap();
+        assert_eq!(xml, "<id>550e-8400</id>");
+    }
+
+    #[test]
+    fn serialize_map_attrs() {
+        use std::collections::BTreeMap;
+
+        #[derive(Serialize)]
+        struct Foo {
+            foo: BTreeMap<&'static str, &'static str>,
+        }
+
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false)
+            .build();
+        let value = Foo {
+            foo: vec![("$attr:id", "1"), ("a", "2"), ("$attr:type", "b")].into_iter().collect(),
+        };
+        assert_xml_eq!(
+            crate::to_string_custom(&value, options).unwrap(),
+            r#"<foo id="1" type="b"><a>2</a></foo>"#
+        );
+    }
+
+    #[test]
+    fn serialize_namespaced_attrs() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Bar {
+            #[serde(rename = "$attr:{http://www.w3.org/2001/XMLSchema-instance}type")]
+            xsi_type: String,
+            #[serde(rename = "$attr:{urn:foo}f:id")]
+            id: String,
+        }
+
+        let value = Foo {
+            foo: Bar {
+                xsi_type: "a".to_string(),
+                id: "1".to_string(),
+            }
+        };
+        for include_schema_location in [true, false] {
+            let options = crate::Options::builder()
+                .include_schema_location(include_schema_location)
+                .write_document_declaration(false)
+                .build();
+            let xml = crate::to_string_custom(&value, options).unwrap();
+            assert!(xml.contains(r#" xsi:type="a""#));
+            assert!(xml.contains(r#" xmlns:f="urn:foo""#));
+            assert_eq!(crate::from_str::<Foo>(&xml).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn serialize_collect_str() {
+        struct Point(i32, i32);
+
+        impl std::fmt::Display for Point {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "{} < {}", self.0, self.1)
+            }
+        }
+
+        impl serde::Serialize for Point {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        #[derive(Serialize)]
+        struct Foo {
+            point: Point,
+        }
+
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false)
+            .build();
+        assert_eq!(
+            crate::to_string_custom(&Foo { point: Point(1, 2) }, options).unwrap(),
+            "<point>1 &lt; 2</point>"
+        );
+    }
+
+    #[test]
+    fn serialize_value_first() {
+        #[derive(Serialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Serialize)]
+        struct Bar {
+            a: String,
+            #[serde(rename = "$value")]
+            value: String,
+        }
+
+        let value = Foo {
+            foo: Bar {
+                a: "1".to_string(),
+                value: "text".to_string(),
+            }
+        };
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false)
+            .indent_size(0);
+        assert_eq!(
+            crate::to_string_custom(&value, options.clone().build()).unwrap(),
+            "<foo>\n<a>1</a>text</foo>"
+        );
+        assert_eq!(
+            crate::to_string_custom(&value, options.value_first(true).build()).unwrap(),
+            "<foo>text<a>1</a>\n</foo>"
+        );
+    }
+
+    #[test]
+    fn round_trip_escaped_attrs() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Bar {
+            #[serde(rename = "$attr:value")]
+            value: String,
+        }
+
+        let value = Foo {
+            foo: Bar {
+                value: r#"a < b & "c""#.to_string(),
+            }
+        };
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false)
+            .build();
+        let xml = crate::to_string_custom(&value, options).unwrap();
+        assert_eq!(xml, r#"<foo value="a &lt; b &amp; &quot;c&quot;"/>"#);
+        assert_eq!(crate::from_str::<Foo>(&xml).unwrap(), value);
+    }
+
+    #[test]
+    fn to_events_escaped_attrs_and_text() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Bar {
+            #[serde(rename = "$attr:value")]
+            value: String,
+            #[serde(rename = "$value")]
+            text: String,
+        }
+
+        let value = Foo {
+            foo: Bar {
+                value: r#"a < b & "c""#.to_string(),
+                text: r#"d < e & "f""#.to_string(),
+            }
+        };
+        let events = crate::to_events(&value).unwrap();
+        // Events hold text as it would be parsed, not as it's written
+        assert!(events.iter().any(|e| matches!(
+            e, xml::reader::XmlEvent::StartElement { attributes, .. } if attributes[0].value == r#"a < b & "c""#
+        )), "{:?}", events);
+        assert!(events.iter().any(|e| matches!(
+            e, xml::reader::XmlEvent::CData(s) if s == r#"d < e & "f""#
+        )), "{:?}", events);
+        let events = events.into_iter().map(Ok).collect::<Vec<_>>();
+        assert_eq!(crate::from_events::<Foo>(&events).unwrap(), value);
+    }
+
+    #[test]
+    fn serialize_seq_of_structs_with_attrs() {
+        #[derive(Serialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Serialize)]
+        struct Bar {
+            item: Vec<Item>,
+        }
+
+        #[derive(Serialize)]
+        struct Item {
+            #[serde(rename = "$attr:id")]
+            id: u32,
+            #[serde(rename = "$attr:lang")]
+            lang: &'static str,
+            name: &'static str,
+        }
+
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false)
+            .build();
+        let value = Foo {
+            foo: Bar {
+                item: vec![
+                    Item { id: 1, lang: "en", name: "a" },
+                    Item { id: 2, lang: "de", name: "b" },
+                ],
+            }
+        };
+        assert_xml_eq!(
+            crate::to_string_custom(&value, options).unwrap(),
+            r#"<foo><item id="1" lang="en"><name>a</name></item><item id="2" lang="de"><name>b</name></item></foo>"#
+        );
+    }
+
+    #[test]
+    fn serialize_invalid_attr_names() {
+        use std::collections::BTreeMap;
+
+        #[derive(Serialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Serialize)]
+        struct Bar {
+            #[serde(rename = "$attr_map")]
+            attrs: BTreeMap<&'static str, &'static str>,
+        }
+
+        let serialize = |name| crate::to_string(&Foo {
+            foo: Bar { attrs: vec![(name, "1")].into_iter().collect() },
+        });
+        assert!(serialize("id").is_ok());
+        assert!(serialize("{urn:foo}f:id").is_ok());
+        assert_eq!(serialize("1id"), Err(crate::Error::InvalidElementName("1id".to_string())));
+        assert_eq!(serialize("a b"), Err(crate::Error::InvalidElementName("a b".to_string())));
+    }
+
+    #[test]
+    fn round_trip_bytes() {
+        #[derive(Debug, PartialEq)]
+        struct Bytes(Vec<u8>);
+
+        impl serde::Serialize for Bytes {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_bytes(&self.0)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for Bytes {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                struct Visitor;
+
+                impl serde::de::Visitor<'_> for Visitor {
+                    type Value = Bytes;
+
+                    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                        f.write_str("bytes")
+                    }
+
+                    fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<Bytes, E> {
+                        Ok(Bytes(v))
+                    }
+                }
+
+                deserializer.deserialize_byte_buf(Visitor)
+            }
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            data: Bytes,
+        }
+
+        let value = Foo { data: Bytes(b"hello world".to_vec()) };
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false)
+            .build();
+        let xml = crate::to_string_custom(&value, options).unwrap();
+        assert_eq!(xml, "<data>68656c6c6f20776f726c64</data>");
+        assert_eq!(crate::from_str::<Foo>(&xml).unwrap(), value);
+        assert!(crate::from_str::<Foo>("<data>hello</data>").is_err());
+    }
+
+    #[test]
+    fn serialize_pad_self_closing() {
+        #[derive(Serialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Serialize)]
+        struct Bar;
+
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false);
+        assert_eq!(crate::to_string_custom(&Foo { foo: Bar }, options.clone().build()).unwrap(), "<foo/>");
+        assert_eq!(
+            crate::to_string_custom(&Foo { foo: Bar }, options.pad_self_closing(true).build()).unwrap(),
+            "<foo />"
+        );
+    }
+
+    #[test]
+    fn round_trip_special_floats() {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Foo {
+            foo: Bar,
+        }
+
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Bar {
+            #[serde(rename = "$attr:attr")]
+            attr: f64,
+            value: f32,
+        }
+
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false)
+            .indent_size(0)
+            .build();
+        let serialize = |attr, value| crate::to_string_custom(&Foo { foo: Bar { attr, value } }, options.clone()).unwrap();
+        assert_eq!(serialize(f64::INFINITY, f32::NEG_INFINITY), "<foo attr=\"INF\">\n<value>-INF</value>\n</foo>");
+        assert_eq!(serialize(f64::NAN, 1.5), "<foo attr=\"NaN\">\n<value>1.5</value>\n</foo>");
+
+        let deserialize = |xml| crate::from_str::<Foo>(xml).unwrap().foo;
+        let bar = deserialize(r#"<foo attr="-INF"><value>NaN</value></foo>"#);
+        assert_eq!(bar.attr, f64::NEG_INFINITY);
+        assert!(bar.value.is_nan());
+        let bar = deserialize(r#"<foo attr="inf"><value>-inf</value></foo>"#);
+        assert_eq!(bar.attr, f64::INFINITY);
+        assert_eq!(bar.value, f32::NEG_INFINITY);
+        let bar = deserialize(r#"<foo attr="-1.5"><value>2.25</value></foo>"#);
+        assert_eq!(bar.attr, -1.5);
+        assert_eq!(bar.value, 2.25);
+
+        for xml in [r#"<foo attr="1"><value>x</value></foo>"#, r#"<foo attr="x"><value>1</value></foo>"#] {
+            assert_eq!(crate::from_str::<Foo>(xml).unwrap_err(), crate::Error::ExpectedFloat);
+        }
+    }
+
+    #[test]
+    fn round_trip_negative_zero() {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Foo {
+            foo: f64,
+        }
+
+        let xml = crate::to_string(&Foo { foo: -0.0 }).unwrap();
+        assert!(xml.ends_with(">-0</foo>"), "{}", xml);
+        let foo = crate::from_str::<Foo>(&xml).unwrap().foo;
+        assert_eq!(foo, 0.0);
+        assert!(foo.is_sign_negative());
+    }
+
+    #[test]
+    fn to_writer_streams_same_output() {
+        #[derive(Serialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:foo")]
+            foo: Bar,
+        }
+
+        #[derive(Serialize)]
+        struct Bar {
+            #[serde(rename = "$attr:id")]
+            id: u32,
+            #[serde(rename = "$attr:missing")]
+            missing: Option<String>,
+            #[serde(rename = "{urn:foo}foo:item")]
+            items: Vec<Item>,
+            nested: Vec<Vec<u32>>,
+            absent: Option<u32>,
+            unit: Status,
+            variant: Variant,
+            map: std::collections::BTreeMap<String, u32>,
+            length: Meters,
+            lengths: Vec<Meters>,
+            list: List,
+        }
+
+        #[derive(Serialize)]
+        struct Item {
+            #[serde(rename = "$attr:n")]
+            n: usize,
+            #[serde(rename = "$value")]
+            text: String,
+        }
+
+        #[derive(Serialize)]
+        enum Status {
+            Ok,
+        }
+
+        #[derive(Serialize)]
+        enum Variant {
+            A { a: u32 },
+        }
+
+        #[derive(Serialize)]
+        struct Meters(u32);
+
+        #[derive(Serialize)]
+        struct List {
+            #[serde(rename = "$list")]
+            values: Vec<u32>,
+        }
+
+        let value = Foo {
+            foo: Bar {
+                id: 1,
+                missing: None,
+                items: (0..3).map(|n| Item { n, text: format!("a < {}", n) }).collect(),
+                nested: vec![vec![1, 2], vec![3]],
+                absent: None,
+                unit: Status::Ok,
+                variant: Variant::A { a: 4 },
+                map: vec![("x".to_string(), 5)].into_iter().collect(),
+                length: Meters(6),
+                lengths: vec![Meters(7), Meters(8)],
+                list: List { values: vec![9, 10] },
+            }
+        };
+        for options in [
+            crate::Options::builder(),
+            crate::Options::builder().include_schema_location(false),
+            crate::Options::builder().hoist_namespaces(true),
+            crate::Options::builder().newtype_struct_as_wrapper(true),
+            crate::Options::builder().absent_as_empty_element(true),
+            crate::Options::builder().unit_variant_as_element(true),
+        ] {
+            let mut out = vec![];
+            crate::to_writer_custom(&mut out, &value, options.clone().build()).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), crate::to_string_custom(&value, options.build()).unwrap());
+        }
+    }
+
+    #[test]
+    fn to_writer_attr_after_element() {
+        #[derive(Serialize)]
+        struct Doc {
+            doc: Foo,
+        }
+
+        #[derive(Serialize)]
+        struct Foo {
+            foo: Vec<Bar>,
+            after: Bar,
+        }
+
+        #[derive(Serialize)]
+        struct Bar {
+            a: Baz,
+            #[serde(rename = "$attr:id")]
+            id: u32,
+            b: Baz,
+            #[serde(rename = "$attr:late")]
+            late: Option<u32>,
+            c: Baz,
+        }
+
+        #[derive(Serialize)]
+        struct Baz {
+            #[serde(rename = "$value")]
+            text: String,
+            #[serde(rename = "$attr:x")]
+            x: u32,
+        }
+
+        let bar = |id, late| Bar {
+            a: Baz { text: "a".to_string(), x: id },
+            id,
+            b: Baz { text: "b".to_string(), x: id },
+            late,
+            c: Baz { text: "c".to_string(), x: id },
+        };
+        let value = Doc { doc: Foo { foo: vec![bar(1, Some(2)), bar(3, None)], after: bar(4, Some(5)) } };
+        let options = crate::Options::builder().indent_size(0);
+        let mut out = vec![];
+        crate::to_writer_custom(&mut out, &value, options.clone().build()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out, crate::to_string_custom(&value, options.build()).unwrap());
+        assert!(out.replace('\n', "").contains(
+            "<foo id=\"1\" late=\"2\"><a x=\"1\">a</a><b x=\"1\">b</b><c x=\"1\">c</c></foo>\
+            <foo id=\"3\"><a x=\"3\">a</a><b x=\"3\">b</b><c x=\"3\">c</c></foo>\
+            <after id=\"4\" late=\"5\"><a x=\"4\">a</a>"
+        ), "{}", out);
+    }
+
+    #[test]
+    fn list_writer_end_element_names() {
+        use super::EventWriter;
+
+        let mut writer = super::ListWriter::default();
+        writer.write(xml::writer::XmlEvent::start_element("a").into()).unwrap();
+        writer.write(xml::writer::XmlEvent::start_element("foo:b").ns("foo", "urn:foo").into()).unwrap();
+        writer.write(xml::writer::XmlEvent::end_element().into()).unwrap();
+        writer.write(xml::writer::XmlEvent::end_element().name("a").into()).unwrap();
+        let names = writer.events.iter().filter_map(|e| match e {
+            xml::reader::XmlEvent::EndElement { name } => Some(name.to_string()),
+            _ => None
+        }).collect::<Vec<_>>();
+        assert_eq!(names, vec!["{urn:foo}foo:b", "a"]);
+
+        // Nothing left open to take the name from
+        assert!(matches!(
+            writer.write(xml::writer::XmlEvent::end_element().into()),
+            Err(xml::writer::Error::EndElementNameIsNotSpecified)
+        ));
+    }
+}
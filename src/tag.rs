@@ -1,18 +1,57 @@
 use std::borrow::Cow;
 
 static NAME_RE: once_cell::sync::Lazy<regex::Regex> = once_cell::sync::Lazy::new(|| {
-    regex::Regex::new(r"^(?:\{(?P<n>[^;]+)(?:;(?P<l>.*))?\})?(?:(?P<p>.+):)?(?P<e>.+)$").unwrap()
+    regex::Regex::new(r"^(?:\{(?P<n>[^;]+)(?:;(?P<l>.*))?\})?(?:(?P<p>.+):)?(?P<e>.*)$").unwrap()
 });
 
-#[derive(Debug,Copy,Clone,Eq,PartialEq)]
-pub(crate) struct Tag<'a> {
+fn is_name_start_char(c: char) -> bool {
+    matches!(c,
+        'A'..='Z' | '_' | 'a'..='z' | '\u{C0}'..='\u{D6}' | '\u{D8}'..='\u{F6}' | '\u{F8}'..='\u{2FF}' |
+        '\u{370}'..='\u{37D}' | '\u{37F}'..='\u{1FFF}' | '\u{200C}'..='\u{200D}' | '\u{2070}'..='\u{218F}' |
+        '\u{2C00}'..='\u{2FEF}' | '\u{3001}'..='\u{D7FF}' | '\u{F900}'..='\u{FDCF}' | '\u{FDF0}'..='\u{FFFD}' |
+        '\u{10000}'..='\u{EFFFF}'
+    )
+}
+
+fn is_name_char(c: char) -> bool {
+    is_name_start_char(c) || matches!(c,
+        '-' | '.' | '0'..='9' | '\u{B7}' | '\u{300}'..='\u{36F}' | '\u{203F}'..='\u{2040}'
+    )
+}
+
+/// Whether a string is a valid XML name without a colon (an NCName), as used for prefixes and
+/// local names
+pub(crate) fn validate_xml_ncname(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if is_name_start_char(c) => chars.all(is_name_char),
+        _ => false
+    }
+}
+
+/// An element or attribute name in the `{namespace;schema}prefix:tag-name` form used for field
+/// names, split into its parts
+///
+/// ```
+/// let tag = "{urn:foo}f:bar".parse::<xml_serde::Tag>().unwrap();
+/// assert_eq!(tag.n, Some("urn:foo"));
+/// assert_eq!(tag.p, Some("f"));
+/// assert_eq!(tag.e, "bar");
+/// ```
+#[derive(Debug,Copy,Clone,Eq,PartialEq,Hash)]
+pub struct Tag<'a> {
+    /// Namespace URI
     pub n: Option<&'a str>,
+    /// Schema location of the namespace
     pub l: Option<&'a str>,
+    /// Prefix
     pub p: Option<&'a str>,
+    /// Local name
     pub e: &'a str,
 }
 
 impl<'a> Tag<'a> {
+    /// Parse a tag, which always succeeds; the local name may be empty, for names such as `{urn:foo}`
     pub fn new(str: &'a str) -> Self {
         let captures = NAME_RE.captures(str).unwrap();
         Self {
@@ -22,8 +61,11 @@ impl<'a> Tag<'a> {
             e: captures.name("e").map(|m| m.as_str()).unwrap(),
         }
     }
+}
 
-    pub fn from_cow(str: &'a Cow<'static, str>) -> Tag<'a> {
+/// Static names are parsed through the cache, and owned ones each time
+impl<'a> From<&'a Cow<'static, str>> for Tag<'a> {
+    fn from(str: &'a Cow<'static, str>) -> Self {
         match str {
             Cow::Borrowed(str) => Tag::from_static(str),
             Cow::Owned(str) => Self::new(str.as_ref()),
@@ -31,7 +73,39 @@ impl<'a> Tag<'a> {
     }
 }
 
+impl Tag<'_> {
+    /// Reconstruct the `{namespace;schema}prefix:tag-name` string this tag was parsed from
+    pub fn to_tag_string(self) -> String {
+        self.to_string()
+    }
+
+    /// Whether the prefix, if any, and the local name are valid XML names (NCNames)
+    pub(crate) fn has_valid_name(&self) -> bool {
+        let prefix_valid = match self.p {
+            Some(p) => validate_xml_ncname(p),
+            None => true
+        };
+        prefix_valid && validate_xml_ncname(self.e)
+    }
+}
+
+impl std::fmt::Display for Tag<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(n) = self.n {
+            match self.l {
+                Some(l) => write!(f, "{{{};{}}}", n, l)?,
+                None => write!(f, "{{{}}}", n)?,
+            }
+        }
+        if let Some(p) = self.p {
+            write!(f, "{}:", p)?;
+        }
+        f.write_str(self.e)
+    }
+}
+
 impl Tag<'static> {
+    /// Parse a tag, caching the result by the string's address so each name is parsed only once
     pub fn from_static(str: &'static str) -> Tag<'static> {
         use once_cell::sync::OnceCell;
         use std::sync::Mutex;
@@ -57,6 +131,22 @@ impl Tag<'static> {
     }
 }
 
+/// Parse a tag from a string only known at runtime.
+///
+/// The string is leaked to give it a `'static` lifetime, so it stays allocated for the rest of the
+/// program; parse each tag once and keep it, rather than parsing repeatedly.
+impl std::str::FromStr for Tag<'static> {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tag = Tag::new(s);
+        if !tag.has_valid_name() {
+            return Err(crate::Error::InvalidElementName(s.to_string()));
+        }
+        Ok(Self::new(Box::leak(s.to_string().into_boxed_str())))
+    }
+}
+
 impl<'a> From<Tag<'a>> for xml::name::Name<'a> {
     fn from(tag: Tag<'a>) -> Self {
         xml::name::Name {
@@ -65,4 +155,51 @@ impl<'a> From<Tag<'a>> for xml::name::Name<'a> {
             prefix: tag.p,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Tag, validate_xml_ncname};
+
+    #[test]
+    fn tag_string_round_trip() {
+        for tag in ["bar", "f:bar", "{urn:foo}bar", "{urn:foo}f:bar", "{urn:foo;foo.xsd}f:bar"] {
+            assert_eq!(Tag::new(tag).to_tag_string(), tag);
+        }
+    }
+
+    #[test]
+    fn parse_tag() {
+        let tag = format!("{{urn:{}}}f:bar", "foo").parse::<Tag<'static>>().unwrap();
+        assert_eq!(tag, Tag::new("{urn:foo}f:bar"));
+        assert_eq!(
+            "".parse::<Tag<'static>>(),
+            Err(crate::Error::InvalidElementName(String::new()))
+        );
+        assert_eq!(
+            "{urn:foo}".parse::<Tag<'static>>(),
+            Err(crate::Error::InvalidElementName("{urn:foo}".to_string()))
+        );
+    }
+
+    #[test]
+    fn validate_ncname() {
+        for name in ["a", "_a", "a-b.c_1", "\u{e9}t\u{e9}", "a\u{300}"] {
+            assert!(validate_xml_ncname(name), "{:?}", name);
+        }
+        for name in ["", "1a", "-a", ".a", "a:b", "a b", "a&b"] {
+            assert!(!validate_xml_ncname(name), "{:?}", name);
+        }
+        assert_eq!(
+            "f:1bar".parse::<Tag<'static>>(),
+            Err(crate::Error::InvalidElementName("f:1bar".to_string()))
+        );
+    }
+
+    #[test]
+    fn empty_local_name() {
+        assert_eq!(Tag::new(""), Tag { n: None, l: None, p: None, e: "" });
+        assert_eq!(Tag::new("{urn:foo}"), Tag { n: Some("urn:foo"), l: None, p: None, e: "" });
+        assert_eq!(Tag::new("f:"), Tag { n: None, l: None, p: Some("f"), e: "" });
+    }
+}
@@ -0,0 +1,305 @@
+//! Dynamic XML values
+//!
+//! `XmlValue` can hold any XML structure, for working with documents that have no fixed schema.
+//! Element and attribute names use the same `{namespace}prefix:tag-name` format as field names;
+//! element names read from a document leave out the prefix.
+
+use serde::{de, ser, Deserialize, Serialize};
+use serde::ser::SerializeMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum XmlValue {
+    Text(String),
+    CData(String),
+    /// An element; an element with an empty name stands for the contents of an element (or of
+    /// the whole document), and is what's produced when deserializing
+    Element { name: String, attrs: Vec<(String, String)>, children: Vec<XmlValue> },
+    Seq(Vec<XmlValue>),
+}
+
+/// Name `XmlValue` asks to be deserialized as, so the deserializer knows to keep all the text in
+/// mixed content
+pub(crate) const XML_VALUE_NAME: &str = "$xml_serde::XmlValue";
+/// Key the deserializer gives CDATA under, where other text is given as `$value`
+pub(crate) const CDATA_KEY: &str = "$cdata";
+
+fn local_name(name: &str) -> &str {
+    if name.is_empty() {
+        name
+    } else {
+        crate::Tag::new(name).e
+    }
+}
+
+impl XmlValue {
+    /// The name of this element, if this is a named element
+    pub fn element_name(&self) -> Option<&str> {
+        match self {
+            XmlValue::Element { name, .. } if !name.is_empty() => Some(name),
+            _ => None
+        }
+    }
+
+    /// The first child element with the given local name
+    pub fn child(&self, local_name: &str) -> Option<&XmlValue> {
+        self.child_elements().iter()
+            .find(|c| c.element_name().map(self::local_name) == Some(local_name))
+    }
+
+    /// All child elements with the given local name
+    pub fn children<'a>(&'a self, local_name: &'a str) -> impl Iterator<Item=&'a XmlValue> + 'a {
+        self.child_elements().iter()
+            .filter(move |c| c.element_name().map(self::local_name) == Some(local_name))
+    }
+
+    /// The value of the attribute with the given local name
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        match self {
+            XmlValue::Element { attrs, .. } => attrs.iter()
+                .find(|(k, _)| local_name(k) == name)
+                .map(|(_, v)| v.as_str()),
+            _ => None
+        }
+    }
+
+    /// The text content of this value; for an element, its first text child
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            XmlValue::Text(s) | XmlValue::CData(s) => Some(s),
+            XmlValue::Element { children, .. } => children.iter().find_map(|c| match c {
+                XmlValue::Text(s) | XmlValue::CData(s) => Some(s.as_str()),
+                _ => None
+            }),
+            XmlValue::Seq(_) => None
+        }
+    }
+
+    fn child_elements(&self) -> &[XmlValue] {
+        match self {
+            XmlValue::Element { children, .. } | XmlValue::Seq(children) => children,
+            _ => &[]
+        }
+    }
+
+    fn into_named(self, name: String) -> Self {
+        match self {
+            XmlValue::Element { name: n, attrs, children } if n.is_empty() => XmlValue::Element {
+                name,
+                attrs,
+                children,
+            },
+            XmlValue::Text(s) | XmlValue::CData(s) if s.is_empty() => XmlValue::Element {
+                name,
+                attrs: vec![],
+                children: vec![],
+            },
+            XmlValue::Seq(children) => XmlValue::Element {
+                name,
+                attrs: vec![],
+                children,
+            },
+            v => XmlValue::Element {
+                name,
+                attrs: vec![],
+                children: vec![v],
+            }
+        }
+    }
+}
+
+impl Serialize for XmlValue {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            XmlValue::Text(s) | XmlValue::CData(s) => serializer.serialize_str(s),
+            XmlValue::Seq(s) => serializer.collect_seq(s),
+            XmlValue::Element { name, .. } if name.is_empty() => ElementContents(self).serialize(serializer),
+            XmlValue::Element { name, .. } => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(name, &ElementContents(self))?;
+                map.end()
+            }
+        }
+    }
+}
+
+/// The attributes and children of an element, without the element itself
+struct ElementContents<'a>(&'a XmlValue);
+
+impl Serialize for ElementContents<'_> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (attrs, children) = match self.0 {
+            XmlValue::Element { attrs, children, .. } => (attrs, children),
+            v => return v.serialize(serializer)
+        };
+        let mut map = serializer.serialize_map(None)?;
+        for (k, v) in attrs {
+            map.serialize_entry(&format!("$attr:{}", k), v)?;
+        }
+        serialize_children(&mut map, children)?;
+        map.end()
+    }
+}
+
+fn serialize_children<M: SerializeMap>(map: &mut M, children: &[XmlValue]) -> Result<(), M::Error> {
+    for child in children {
+        match child {
+            XmlValue::Text(s) | XmlValue::CData(s) => map.serialize_entry("$value", s)?,
+            XmlValue::Element { name, children, .. } if name.is_empty() => serialize_children(map, children)?,
+            XmlValue::Element { name, .. } => map.serialize_entry(name, &ElementContents(child))?,
+            XmlValue::Seq(s) => serialize_children(map, s)?,
+        }
+    }
+    Ok(())
+}
+
+impl<'de> Deserialize<'de> for XmlValue {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_newtype_struct(XML_VALUE_NAME, XmlValueVisitor)
+    }
+}
+
+struct XmlValueVisitor;
+
+impl<'de> de::Visitor<'de> for XmlValueVisitor {
+    type Value = XmlValue;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("any XML value")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<XmlValue, E> {
+        Ok(XmlValue::Text(v.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<XmlValue, E> {
+        Ok(XmlValue::Text(v))
+    }
+
+    /// Other deserializers don't know the name, so read whatever they have
+    fn visit_newtype_struct<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<XmlValue, D::Error> {
+        deserializer.deserialize_any(XmlValueVisitor)
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<XmlValue, A::Error> {
+        let mut values = vec![];
+        while let Some(v) = seq.next_element()? {
+            values.push(v);
+        }
+        Ok(XmlValue::Seq(values))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<XmlValue, A::Error> {
+        let mut attrs = vec![];
+        let mut children = vec![];
+        while let Some(key) = map.next_key::<String>()? {
+            if let Some(attr) = key.strip_prefix("$attr:") {
+                attrs.push((attr.to_string(), map.next_value::<String>()?));
+            } else if key == CDATA_KEY {
+                children.push(XmlValue::CData(map.next_value::<String>()?));
+            } else if key.starts_with("$value") {
+                children.push(XmlValue::Text(map.next_value::<String>()?));
+            } else {
+                children.push(map.next_value::<XmlValue>()?.into_named(key));
+            }
+        }
+        Ok(XmlValue::Element {
+            name: String::new(),
+            attrs,
+            children,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::XmlValue;
+
+    #[test]
+    fn round_trip_xml_value() {
+        let input = r#"<foo:bar xmlns:foo="urn:foo" id="1"><foo:a>x &amp; y</foo:a><foo:b c="2"><foo:d/></foo:b><foo:a>z</foo:a></foo:bar>"#;
+        let value = crate::from_str::<XmlValue>(input).unwrap();
+        assert_eq!(value, XmlValue::Element {
+            name: String::new(),
+            attrs: vec![],
+            children: vec![XmlValue::Element {
+                name: "{urn:foo}bar".to_string(),
+                attrs: vec![("id".to_string(), "1".to_string())],
+                children: vec![
+                    XmlValue::Element {
+                        name: "{urn:foo}a".to_string(),
+                        attrs: vec![],
+                        children: vec![XmlValue::Text("x & y".to_string())],
+                    },
+                    XmlValue::Element {
+                        name: "{urn:foo}b".to_string(),
+                        attrs: vec![("c".to_string(), "2".to_string())],
+                        children: vec![XmlValue::Element {
+                            name: "{urn:foo}d".to_string(),
+                            attrs: vec![],
+                            children: vec![],
+                        }],
+                    },
+                    XmlValue::Element {
+                        name: "{urn:foo}a".to_string(),
+                        attrs: vec![],
+                        children: vec![XmlValue::Text("z".to_string())],
+                    },
+                ],
+            }],
+        });
+
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .indent_size(0)
+            .build();
+        let output = crate::to_string_custom(&value, options).unwrap();
+        assert_eq!(crate::from_str::<XmlValue>(&output).unwrap(), value);
+    }
+
+    #[test]
+    fn query_xml_value() {
+        let value = crate::from_str::<XmlValue>(
+            r#"<foo:bar xmlns:foo="urn:foo" foo:id="1"><foo:a>x</foo:a><foo:b/><foo:a>y</foo:a></foo:bar>"#
+        ).unwrap();
+        let bar = value.child("bar").unwrap();
+        assert_eq!(bar.element_name(), Some("{urn:foo}bar"));
+        assert_eq!(bar.attr("id"), Some("1"));
+        assert_eq!(bar.attr("missing"), None);
+        assert_eq!(bar.child("a").and_then(XmlValue::text), Some("x"));
+        assert_eq!(bar.children("a").filter_map(XmlValue::text).collect::<Vec<_>>(), vec!["x", "y"]);
+        assert_eq!(bar.child("b").and_then(XmlValue::text), None);
+        assert!(bar.child("c").is_none());
+    }
+
+    #[test]
+    fn mixed_content_xml_value() {
+        let input = "<p>Hello <b>world</b>!<![CDATA[ <i> ]]>bye</p>";
+        let config = crate::DeserializerConfig {
+            trim_whitespace: false,
+            ..crate::DeserializerConfig::default()
+        };
+        let value = crate::from_str_with_config::<XmlValue>(input, config).unwrap();
+        assert_eq!(value.child("p").unwrap(), &XmlValue::Element {
+            name: "p".to_string(),
+            attrs: vec![],
+            children: vec![
+                XmlValue::Text("Hello ".to_string()),
+                XmlValue::Element {
+                    name: "b".to_string(),
+                    attrs: vec![],
+                    children: vec![XmlValue::Text("world".to_string())],
+                },
+                XmlValue::Text("!".to_string()),
+                XmlValue::CData(" <i> ".to_string()),
+                XmlValue::Text("bye".to_string()),
+            ],
+        });
+
+        let options = crate::Options::builder()
+            .include_schema_location(false)
+            .write_document_declaration(false)
+            .indent_size(0)
+            .build();
+        assert_eq!(crate::to_string_custom(&value, options).unwrap(), "<p>Hello <b>world</b>! &lt;i&gt; bye</p>");
+    }
+}